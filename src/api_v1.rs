@@ -0,0 +1,370 @@
+use crate::audit::EntryAudit;
+use crate::health;
+use crate::types::*;
+use crate::utils::*;
+
+use actix_web::{http::header::ContentType, web, HttpRequest, HttpResponse};
+
+fn check_name_conflict(rtodo: &Rtodo, entry: &Entry) -> Result<(), ApiError> {
+  match rtodo
+    .config
+    .entries
+    .iter()
+    .find(|e| e.name == entry.name && e.id != entry.id)
+  {
+    Some(e) => Err(ApiError::new(
+      ApiErrorKind::Conflict,
+      format!("Entry {} already uses name {}", e.id, e.name),
+    )),
+    None => Ok(()),
+  }
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/v1/entries",
+  responses(
+    (status = 200, description = "All configured entries", body = Vec<Entry>),
+    (status = 401, description = "Missing or invalid bearer token", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+async fn get_entries(req: HttpRequest, state: RS) -> Result<HttpResponse, ApiError> {
+  let token = api_token(&req, None);
+  let entries = state
+    .read(move |rtodo| {
+      let grant = check_request_token(token.as_deref(), rtodo, TokenScope::Read)?;
+      Ok::<Vec<Entry>, ApiError>(
+        rtodo
+          .get_entries()
+          .into_iter()
+          .filter(|entry| grant.covers_entry(entry))
+          .collect(),
+      )
+    })
+    .await??;
+  Ok(HttpResponse::Ok().json(entries))
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/v1/entries/{id}",
+  params(("id" = String, Path, description = "Entry id or name")),
+  responses(
+    (status = 200, description = "The entry", body = Entry),
+    (status = 401, description = "Missing or invalid bearer token", body = ApiError),
+    (status = 403, description = "The token's scope or entries don't allow this", body = ApiError),
+    (status = 404, description = "Entry not found", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+async fn get_entry(
+  req: HttpRequest,
+  path: web::Path<String>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let token = api_token(&req, None);
+  let identifier = EntryIdentifier::parse(&path);
+  let entry = state
+    .read(move |rtodo| {
+      let grant = check_request_token(token.as_deref(), rtodo, TokenScope::Read)?;
+      match rtodo.find_entry(&identifier) {
+        Some(entry) => {
+          grant
+            .require_entry(TokenScope::Read, entry)
+            .map_err(ApiError::forbidden)?;
+          Ok(entry.clone())
+        }
+        None => Err(ApiError::entry_not_found(&identifier)),
+      }
+    })
+    .await??;
+  Ok(HttpResponse::Ok().json(entry))
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/v1/entries",
+  request_body = Entry,
+  responses(
+    (status = 201, description = "The created entry with its assigned id", body = Entry),
+    (status = 401, description = "Missing or invalid bearer token", body = ApiError),
+    (status = 403, description = "The token's scope or entries don't allow this", body = ApiError),
+    (status = 409, description = "Another entry already uses the name", body = ApiError),
+    (status = 422, description = "Invalid request body", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+async fn create_entry(
+  req: HttpRequest,
+  entry: web::Json<Entry>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let (origin, token) = (Origin::new(&req), api_token(&req, None));
+  let entry = entry.into_inner();
+  let created = state
+    .write(move |rtodo| {
+      let grant = request_grant(token.as_deref(), rtodo)?;
+      // By the id add_entry is about to assign.
+      let identifier = EntryIdentifier::Id(rtodo.cur_entry_id + 1);
+      let audit = EntryAudit::begin(&origin, &grant, rtodo, &identifier);
+      let res = create(rtodo, &grant, entry);
+      audit.finish(rtodo, &res);
+      res
+    })
+    .await??;
+  Ok(HttpResponse::Created().json(created))
+}
+
+fn create(rtodo: &mut Rtodo, grant: &Grant, mut entry: Entry) -> Result<Entry, ApiError> {
+  entry.id = 0;
+  grant
+    .require_entry(TokenScope::Admin, &entry)
+    .map_err(ApiError::forbidden)?;
+  check_name_conflict(rtodo, &entry)?;
+  rtodo.add_entry(entry).map_err(ApiError::internal)?;
+  let identifier = EntryIdentifier::Id(rtodo.cur_entry_id);
+  rtodo.find_entry(&identifier).cloned().ok_or_else(|| {
+    ApiError::new(
+      ApiErrorKind::Internal,
+      format!("Entry {} is missing after it was added", identifier),
+    )
+  })
+}
+
+#[utoipa::path(
+  put,
+  path = "/api/v1/entries/{id}",
+  params(("id" = String, Path, description = "Entry id or name")),
+  request_body = Entry,
+  responses(
+    (status = 200, description = "The replaced entry", body = Entry),
+    (status = 401, description = "Missing or invalid bearer token", body = ApiError),
+    (status = 403, description = "The token's scope or entries don't allow this", body = ApiError),
+    (status = 404, description = "Entry not found", body = ApiError),
+    (status = 409, description = "Another entry already uses the name", body = ApiError),
+    (status = 422, description = "Invalid request body", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+async fn replace_entry(
+  req: HttpRequest,
+  path: web::Path<String>,
+  entry: web::Json<Entry>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let (origin, token) = (Origin::new(&req), api_token(&req, None));
+  let identifier = EntryIdentifier::parse(&path);
+  let entry = entry.into_inner();
+  let replaced = state
+    .write(move |rtodo| {
+      let grant = request_grant(token.as_deref(), rtodo)?;
+      let audit = EntryAudit::begin(&origin, &grant, rtodo, &identifier);
+      let res = replace(rtodo, &grant, &identifier, entry);
+      audit.finish(rtodo, &res);
+      res
+    })
+    .await??;
+  Ok(HttpResponse::Ok().json(replaced))
+}
+
+fn replace(
+  rtodo: &mut Rtodo,
+  grant: &Grant,
+  identifier: &EntryIdentifier,
+  mut entry: Entry,
+) -> Result<Entry, ApiError> {
+  let id = match rtodo.find_entry(identifier) {
+    Some(e) => {
+      grant
+        .require_entry(TokenScope::Admin, e)
+        .map_err(ApiError::forbidden)?;
+      e.id
+    }
+    None => return Err(ApiError::entry_not_found(identifier)),
+  };
+  entry.id = id;
+  grant
+    .require_entry(TokenScope::Admin, &entry)
+    .map_err(ApiError::forbidden)?;
+  check_name_conflict(rtodo, &entry)?;
+  rtodo.edit_entry(&entry).map_err(ApiError::internal)?;
+  Ok(entry)
+}
+
+#[utoipa::path(
+  patch,
+  path = "/api/v1/entries/{id}",
+  params(("id" = String, Path, description = "Entry id or name")),
+  request_body(content = Object, description = "JSON merge patch applied to the entry"),
+  responses(
+    (status = 200, description = "The patched entry", body = Entry),
+    (status = 401, description = "Missing or invalid bearer token", body = ApiError),
+    (status = 403, description = "The token's scope or entries don't allow this", body = ApiError),
+    (status = 404, description = "Entry not found", body = ApiError),
+    (status = 409, description = "Another entry already uses the name", body = ApiError),
+    (status = 422, description = "Invalid request body", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+async fn patch_entry(
+  req: HttpRequest,
+  path: web::Path<String>,
+  patch: web::Json<serde_json::Value>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let (origin, token) = (Origin::new(&req), api_token(&req, None));
+  let identifier = EntryIdentifier::parse(&path);
+  let patch = patch.into_inner();
+  let patched = state
+    .write(move |rtodo| {
+      let grant = request_grant(token.as_deref(), rtodo)?;
+      let audit = EntryAudit::begin(&origin, &grant, rtodo, &identifier);
+      let res = apply_patch(rtodo, &grant, &identifier, &patch);
+      audit.finish(rtodo, &res);
+      res
+    })
+    .await??;
+  Ok(HttpResponse::Ok().json(patched))
+}
+
+fn apply_patch(
+  rtodo: &mut Rtodo,
+  grant: &Grant,
+  identifier: &EntryIdentifier,
+  patch: &serde_json::Value,
+) -> Result<Entry, ApiError> {
+  let current = match rtodo.find_entry(identifier) {
+    Some(e) => e.clone(),
+    None => return Err(ApiError::entry_not_found(identifier)),
+  };
+  grant
+    .require_entry(TokenScope::Admin, &current)
+    .map_err(ApiError::forbidden)?;
+  if !patch.is_object() {
+    return Err(ApiError::new(
+      ApiErrorKind::Invalid,
+      "Patch body must be a JSON object",
+    ));
+  }
+  let mut value = serde_json::to_value(&current).map_err(|err| ApiError::internal(err.into()))?;
+  json_merge(&mut value, patch);
+  let mut entry: Entry = serde_json::from_value(value)
+    .map_err(|err| ApiError::new(ApiErrorKind::Invalid, err.to_string()))?;
+  entry.id = current.id;
+  grant
+    .require_entry(TokenScope::Admin, &entry)
+    .map_err(ApiError::forbidden)?;
+  check_name_conflict(rtodo, &entry)?;
+  rtodo.edit_entry(&entry).map_err(ApiError::internal)?;
+  Ok(entry)
+}
+
+#[utoipa::path(
+  delete,
+  path = "/api/v1/entries/{id}",
+  params(("id" = String, Path, description = "Entry id or name")),
+  responses(
+    (status = 204, description = "The entry was deleted"),
+    (status = 401, description = "Missing or invalid bearer token", body = ApiError),
+    (status = 403, description = "The token's scope or entries don't allow this", body = ApiError),
+    (status = 404, description = "Entry not found", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+async fn delete_entry(
+  req: HttpRequest,
+  path: web::Path<String>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let (origin, token) = (Origin::new(&req), api_token(&req, None));
+  let identifier = EntryIdentifier::parse(&path);
+  state
+    .write(move |rtodo| {
+      let grant = request_grant(token.as_deref(), rtodo)?;
+      let audit = EntryAudit::begin(&origin, &grant, rtodo, &identifier);
+      let res = delete(rtodo, &grant, &identifier);
+      audit.finish(rtodo, &res);
+      res
+    })
+    .await??;
+  Ok(HttpResponse::NoContent().finish())
+}
+
+fn delete(rtodo: &mut Rtodo, grant: &Grant, identifier: &EntryIdentifier) -> Result<(), ApiError> {
+  match rtodo.find_entry(identifier) {
+    Some(entry) => grant
+      .require_entry(TokenScope::Admin, entry)
+      .map_err(ApiError::forbidden)?,
+    None => return Err(ApiError::entry_not_found(identifier)),
+  }
+  rtodo.delete_entry(identifier).map_err(ApiError::internal)
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/v1/works",
+  responses(
+    (status = 200, description = "Runtime state of all enabled entries", body = Vec<Work>),
+    (status = 401, description = "Missing or invalid bearer token", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+async fn get_works(req: HttpRequest, state: RS) -> Result<HttpResponse, ApiError> {
+  let token = api_token(&req, None);
+  // Serialized on the core rather than cloning every work out of it.
+  let works = state
+    .read(move |rtodo| {
+      let grant = check_request_token(token.as_deref(), rtodo, TokenScope::Read)?;
+      serde_json::to_string(&rtodo.get_works(&grant)).map_err(|err| ApiError::internal(err.into()))
+    })
+    .await??;
+  Ok(
+    HttpResponse::Ok()
+      .content_type(ContentType::json())
+      .body(works),
+  )
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/v1/status",
+  responses(
+    (status = 200, description = "Pid, version and uptime of the daemon", body = DaemonInfo),
+    (status = 401, description = "Missing or invalid bearer token", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+async fn get_status(req: HttpRequest, state: RS) -> Result<HttpResponse, ApiError> {
+  let token = api_token(&req, None);
+  let stopping = state
+    .read(move |rtodo| {
+      check_request_token(token.as_deref(), rtodo, TokenScope::Read)?;
+      Ok::<bool, ApiError>(rtodo.is_stopping())
+    })
+    .await??;
+  let started_at = health::started_at();
+  Ok(HttpResponse::Ok().json(DaemonInfo {
+    pid: std::process::id(),
+    version: env!("CARGO_PKG_VERSION").to_string(),
+    started_at,
+    uptime: DateTime::now().timestamp - started_at,
+    stopping,
+  }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+  cfg
+    .app_data(
+      web::JsonConfig::default()
+        .error_handler(|err, _| ApiError::new(ApiErrorKind::Invalid, err.to_string()).into()),
+    )
+    .route("/entries", web::get().to(get_entries))
+    .route("/entries", web::post().to(create_entry))
+    .route("/entries/{id}", web::get().to(get_entry))
+    .route("/entries/{id}", web::put().to(replace_entry))
+    .route("/entries/{id}", web::patch().to(patch_entry))
+    .route("/entries/{id}", web::delete().to(delete_entry))
+    .route("/works", web::get().to(get_works))
+    .route("/status", web::get().to(get_status));
+}
@@ -0,0 +1,166 @@
+use crate::types::*;
+use crate::utils::*;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::error;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_LIMIT: usize = 100;
+
+fn rotated(path: &Path, index: u32) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(format!(".{}", index));
+  PathBuf::from(name)
+}
+
+fn rotate(path: &Path, keep: u32) -> Result<(), Box<dyn Error>> {
+  if keep == 0 {
+    fs::remove_file(path)?;
+    return Ok(());
+  }
+  for index in (1..keep).rev() {
+    let from = rotated(path, index);
+    if from.exists() {
+      fs::rename(&from, rotated(path, index + 1))?;
+    }
+  }
+  fs::rename(path, rotated(path, 1))?;
+  Ok(())
+}
+
+fn append(path: &Path, audit: &AuditConfig, record: &AuditRecord) -> Result<(), Box<dyn Error>> {
+  if fs::metadata(path).is_ok_and(|meta| meta.len() >= audit.max_size) {
+    rotate(path, audit.keep)?;
+  }
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let mut options = OpenOptions::new();
+  options.create(true).append(true);
+  #[cfg(target_family = "unix")]
+  options.mode(0o600);
+  let mut line = serde_json::to_vec(record)?;
+  line.push(b'\n');
+  options.open(path)?.write_all(&line)?;
+  Ok(())
+}
+
+/// Failing to record doesn't fail the operation, it is only logged.
+pub fn record(rtodo: &Rtodo, record: AuditRecord) {
+  if !rtodo.config.audit.enabled {
+    return;
+  }
+  let path = rtodo.audit_path();
+  if let Err(err) = append(&path, &rtodo.config.audit, &record) {
+    error!("Error: Failed to write audit log: {:?}, Err: {}", path, err);
+  }
+}
+
+/// An operation on an entry, recorded once done along with how it changed
+/// the entry.
+pub struct EntryAudit {
+  record: AuditRecord,
+  identifier: EntryIdentifier,
+  before: Option<Entry>,
+}
+
+impl EntryAudit {
+  pub fn begin(
+    origin: &Origin,
+    grant: &Grant,
+    rtodo: &Rtodo,
+    identifier: &EntryIdentifier,
+  ) -> Self {
+    Self {
+      record: AuditRecord::new(origin, grant),
+      identifier: identifier.clone(),
+      before: rtodo.find_entry(identifier).cloned(),
+    }
+  }
+
+  pub fn finish<T, E: Display>(self, rtodo: &Rtodo, res: &Result<T, E>) {
+    let after = match &self.before {
+      // By id, the operation may have renamed it.
+      Some(before) => rtodo.find_entry(&EntryIdentifier::Id(before.id)),
+      None if res.is_ok() => rtodo.find_entry(&self.identifier),
+      None => None,
+    };
+    record(
+      rtodo,
+      self
+        .record
+        .with_entry(&self.identifier, self.before.as_ref(), after)
+        .with_result(res),
+    );
+  }
+}
+
+/// The most recent matching records over the log and its rotations, oldest first.
+fn read(path: &Path, keep: u32, query: &AuditQuery) -> Result<Vec<AuditRecord>, Box<dyn Error>> {
+  let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+  let mut records: Vec<AuditRecord> = Vec::new();
+  for index in 0..=keep {
+    if records.len() >= limit {
+      break;
+    }
+    let file = match index {
+      0 => path.to_path_buf(),
+      index => rotated(path, index),
+    };
+    let content = match fs::read_to_string(&file) {
+      Ok(content) => content,
+      Err(err) if err.kind() == ErrorKind::NotFound => continue,
+      Err(err) => return Err(err.into()),
+    };
+    let mut older: Vec<AuditRecord> = content
+      .lines()
+      .filter_map(|line| serde_json::from_str::<AuditRecord>(line).ok())
+      .filter(|record| {
+        query
+          .entry
+          .as_ref()
+          .is_none_or(|entry| record.entry.as_ref() == Some(entry))
+          && query.by.as_ref().is_none_or(|by| record.token == *by)
+      })
+      .collect();
+    older.append(&mut records);
+    records = older;
+  }
+  records.drain(..records.len().saturating_sub(limit));
+  Ok(records)
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/audit",
+  params(AuditQuery),
+  responses(
+    (status = 200, description = "Most recent audit records, oldest first", body = Vec<AuditRecord>),
+    (status = 401, description = "Missing or invalid token", body = ApiError),
+    (status = 403, description = "Only admin tokens for all entries may read the audit log", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+pub async fn get_audit(
+  req: HttpRequest,
+  query: web::Query<AuditQuery>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let token = api_token(&req, None);
+  let (path, keep) = state
+    .read(move |rtodo| {
+      check_request_token(token.as_deref(), rtodo, TokenScope::Admin)?
+        .require_all(TokenScope::Admin)
+        .map_err(ApiError::forbidden)?;
+      Ok::<_, ApiError>((rtodo.audit_path(), rtodo.config.audit.keep))
+    })
+    .await??;
+  let records = read(&path, keep, &query).map_err(ApiError::internal)?;
+  Ok(HttpResponse::Ok().json(records))
+}
@@ -0,0 +1,264 @@
+use crate::types::Limits;
+
+use log::warn;
+use nix::fcntl::{open, OFlag};
+use nix::libc;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, write, Pid};
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::OnceLock;
+
+/// Where runs get their cgroup, see `Config::cgroup`.
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Watches the `cgroup.events` of runs, which change as they empty.
+static EVENTS: OnceLock<Inotify> = OnceLock::new();
+
+/// The controllers behind the cgroup limits of `Limits`.
+const CONTROLLERS: [&str; 3] = ["cpu", "memory", "pids"];
+
+/// Period of `cpu.max` in microseconds, the kernel's default.
+const CPU_PERIOD: u64 = 100_000;
+
+/// The cgroup the daemon runs in, when it was delegated to it, like systemd
+/// does with `Delegate=yes`. Used when `Config::cgroup` isn't set.
+pub fn delegated() -> Option<PathBuf> {
+  let own = fs::read_to_string("/proc/self/cgroup").ok()?;
+  let path = own.lines().find_map(|line| line.strip_prefix("0::"))?;
+  if path == "/" {
+    return None;
+  }
+  let dir = Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/'));
+  let dir_name = CString::new(dir.as_os_str().as_bytes()).ok()?;
+  // systemd marks the cgroups it delegates, for privileged managers with the
+  // trusted xattr and for user managers with the user one.
+  [c"trusted.delegate", c"user.delegate"]
+    .iter()
+    .any(|attr| unsafe {
+      libc::getxattr(dir_name.as_ptr(), attr.as_ptr(), ptr::null_mut(), 0) >= 0
+    })
+    .then_some(dir)
+}
+
+/// Tracks runs by cgroup from here on, `root` must be a cgroup v2 directory
+/// delegated to the daemon.
+pub fn init(root: &Path) -> Result<(), Box<dyn Error>> {
+  if !root.join("cgroup.procs").is_file() {
+    return Err(format!("{:?} is not a cgroup v2 directory", root).into());
+  }
+  // Only cgroups without processes of their own can pass controllers on, and
+  // systemd starts the daemon in the cgroup it delegates.
+  let pid = process::id().to_string();
+  if fs::read_to_string(root.join("cgroup.procs"))?
+    .lines()
+    .any(|line| line == pid)
+  {
+    let leaf = root.join("daemon");
+    if !leaf.is_dir() {
+      fs::create_dir(&leaf)?;
+    }
+    fs::write(leaf.join("cgroup.procs"), &pid)
+      .map_err(|err| format!("Cannot move the daemon to {:?}, Err: {}", leaf, err))?;
+  }
+  let available = fs::read_to_string(root.join("cgroup.controllers"))?;
+  for controller in CONTROLLERS {
+    let res = match available.split_whitespace().any(|name| name == controller) {
+      true => fs::write(
+        root.join("cgroup.subtree_control"),
+        format!("+{}", controller),
+      ),
+      false => Err(io::Error::new(io::ErrorKind::NotFound, "not available")),
+    };
+    if let Err(err) = res {
+      warn!(
+        "Warn: Cannot enable the {} controller in {:?}, runs with its limits won't start, Err: {}",
+        controller, root, err
+      );
+    }
+  }
+  let _ = EVENTS.set(Inotify::init(
+    InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC,
+  )?);
+  let _ = ROOT.set(root.to_path_buf());
+  Ok(())
+}
+
+/// Readable once a run's cgroup changed, `None` if runs aren't tracked by
+/// cgroup. See `drain_events`.
+pub fn events_fd() -> Option<RawFd> {
+  EVENTS.get().map(|events| events.as_raw_fd())
+}
+
+/// Clears what made `events_fd` readable.
+pub fn drain_events() {
+  if let Some(events) = EVENTS.get() {
+    while matches!(events.read_events(), Ok(read) if !read.is_empty()) {}
+  }
+}
+
+/// Creates the cgroup of a run with the cgroup limits in `limits`, `None` if
+/// runs aren't tracked by cgroup. Fails rather than start a run whose limits
+/// can't be enforced.
+pub fn create(run_id: u64, limits: Option<&Limits>) -> Result<Option<PathBuf>, Box<dyn Error>> {
+  let root = match (ROOT.get(), limits.is_some_and(Limits::need_cgroup)) {
+    (Some(root), _) => root,
+    (None, false) => return Ok(None),
+    (None, true) => {
+      return Err(
+        "The memory, cpus and pids limits need a cgroup, set cgroup in the config or delegate one to the daemon"
+          .into(),
+      )
+    }
+  };
+  sweep(root);
+  let path = root.join(format!("run-{}", run_id));
+  fs::create_dir(&path).map_err(|err| format!("Cannot create cgroup {:?}, Err: {}", path, err))?;
+  // Before anything runs in it, so that no change goes unnoticed. The watch
+  // goes away with the cgroup.
+  if let Err(err) = EVENTS
+    .get()
+    .ok_or(nix::Error::EBADF)
+    .and_then(|events| events.add_watch(&path.join("cgroup.events"), AddWatchFlags::IN_MODIFY))
+  {
+    remove(&path);
+    return Err(format!("Cannot watch cgroup {:?}, Err: {}", path, err).into());
+  }
+  if let Some(limits) = limits {
+    if let Err(err) = limit(&path, limits) {
+      remove(&path);
+      return Err(err);
+    }
+  }
+  Ok(Some(path))
+}
+
+fn limit(cgroup: &Path, limits: &Limits) -> Result<(), Box<dyn Error>> {
+  let cpu = limits.cpus.map(|cpus| {
+    format!(
+      "{} {}",
+      (cpus * CPU_PERIOD as f64).max(1000.0) as u64,
+      CPU_PERIOD
+    )
+  });
+  for (controller, file, value) in [
+    (
+      "memory",
+      "memory.max",
+      limits.memory.map(|bytes| bytes.to_string()),
+    ),
+    ("cpu", "cpu.max", cpu),
+    ("pids", "pids.max", limits.pids.map(|pids| pids.to_string())),
+  ] {
+    let Some(value) = value else { continue };
+    let path = cgroup.join(file);
+    // Missing when the controller isn't enabled, `init` warned about it.
+    if !path.exists() {
+      return Err(
+        format!(
+          "Cannot set {}, the {} controller is not enabled in {:?}",
+          file,
+          controller,
+          cgroup.parent().unwrap_or(cgroup)
+        )
+        .into(),
+      );
+    }
+    fs::write(&path, &value)
+      .map_err(|err| format!("Cannot set {} to {}, Err: {}", file, value, err))?;
+  }
+  Ok(())
+}
+
+/// The cgroup limits the run ran into, read from its event counters.
+pub fn limits_hit(cgroup: &Path) -> Vec<String> {
+  let mut hit = Vec::new();
+  let oom_kills = counter(&cgroup.join("memory.events"), "oom_kill");
+  if oom_kills > 0 {
+    hit.push(format!(
+      "memory limit, {} processes killed by the OOM killer",
+      oom_kills
+    ));
+  }
+  let refused = counter(&cgroup.join("pids.events"), "max");
+  if refused > 0 {
+    hit.push(format!("pids limit, {} forks refused", refused));
+  }
+  hit
+}
+
+/// A counter of a flat keyed file like `memory.events`, 0 if there is none.
+fn counter(file: &Path, key: &str) -> u64 {
+  fs::read_to_string(file)
+    .ok()
+    .and_then(|events| {
+      events.lines().find_map(|line| match line.split_once(' ') {
+        Some((name, value)) if name == key => value.parse().ok(),
+        _ => None,
+      })
+    })
+    .unwrap_or(0)
+}
+
+/// Removes the cgroups of killed runs, they only empty after the run was
+/// recorded.
+fn sweep(root: &Path) {
+  for dir in fs::read_dir(root).into_iter().flatten().flatten() {
+    let path = dir.path();
+    if dir.file_name().as_bytes().starts_with(b"run-") && !is_populated(&path) {
+      remove(&path);
+    }
+  }
+}
+
+pub fn remove(cgroup: &Path) {
+  let _ = fs::remove_dir(cgroup);
+}
+
+/// The file `enter` writes to, prepared before forking.
+pub fn procs_file(cgroup: &Path) -> io::Result<CString> {
+  Ok(CString::new(
+    cgroup.join("cgroup.procs").as_os_str().as_bytes(),
+  )?)
+}
+
+/// Moves the calling process into the cgroup of `procs`. Runs between fork
+/// and exec, so it sticks to async-signal-safe calls.
+pub fn enter(procs: &CStr) -> io::Result<()> {
+  let fd = open(procs, OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+  let res = write(fd, b"0");
+  let _ = close(fd);
+  res?;
+  Ok(())
+}
+
+/// Whether any process is left in the cgroup.
+pub fn is_populated(cgroup: &Path) -> bool {
+  fs::read_to_string(cgroup.join("cgroup.events"))
+    .is_ok_and(|events| events.lines().any(|line| line == "populated 1"))
+}
+
+/// Sends `signal` to every process in the cgroup, including those that left
+/// the run's process group.
+pub fn signal(cgroup: &Path, signal: Signal) -> io::Result<()> {
+  // Atomic, unlike going over the processes while they fork. Since Linux 5.14.
+  if signal == Signal::SIGKILL && fs::write(cgroup.join("cgroup.kill"), "1").is_ok() {
+    return Ok(());
+  }
+  for pid in fs::read_to_string(cgroup.join("cgroup.procs"))?
+    .lines()
+    .filter_map(|pid| pid.parse().ok())
+  {
+    let _ = kill(Pid::from_raw(pid), signal);
+  }
+  Ok(())
+}
@@ -1,307 +1,529 @@
+use crate::cgroup;
+use crate::health::{self, Loop};
+use crate::scheduler::{self, Schedule, IDLE_WAKEUP};
 use crate::server::start_server;
+use crate::systemd::{self, Reporter};
 use crate::types::*;
-use crate::utils::check_if_process_by_pid_alive;
-use ctrlc;
-use log::{error, info};
+use crate::utils::{init_output_dir, reap_process};
+use log::{error, info, warn};
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::Signal;
+use nix::sys::wait::waitpid;
+use nix::unistd::{dup2, fork, setsid, ForkResult};
 use std::error::Error;
-use std::process::exit;
-use std::sync::{Arc, RwLock};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::iter;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::process::{self, exit};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
 use std::thread;
-use std::time;
+use std::time::{self, Instant};
 
-pub fn start_executor(rtodo_rwl: Arc<RwLock<Rtodo>>) {
-  {
-    match rtodo_rwl.write() {
-      Ok(data) => {
-        #[cfg(debug_assertions)]
-        info!(
-          "Info: got write lock of works at line:{}, file: {}",
-          line!(),
-          file!()
-        );
-        data
+/// Kept open for the lifetime of the daemon, closing it releases the lock.
+static PID_FILE: OnceLock<File> = OnceLock::new();
+
+/// Set once the core began to stop, a server going away then is expected.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// How long terminated jobs get to exit before they are killed, and killed
+/// ones before they are left behind.
+pub const KILL_GRACE: time::Duration = time::Duration::from_secs(5);
+
+/// A stop in progress, see `ShutdownConfig`.
+struct Shutdown {
+  policy: ShutdownPolicy,
+  /// When `policy` is applied to the jobs still running.
+  deadline: Instant,
+}
+
+impl Shutdown {
+  fn begin(rtodo: &mut Rtodo) -> Self {
+    let config = &rtodo.config.shutdown;
+    let mut running = 0;
+    let mut dropped = 0;
+    for work in rtodo.works.iter_mut() {
+      running += work.running_processes.len();
+      dropped += std::mem::take(&mut work.queued_runs).len();
+    }
+    info!(
+      "Info: Waiting up to {}s for {} running jobs, then {:?}, {} queued runs dropped",
+      config.timeout, running, config.on_timeout, dropped
+    );
+    systemd::notify("STOPPING=1");
+    STOPPING.store(true, Ordering::Relaxed);
+    Self {
+      policy: config.on_timeout,
+      deadline: Instant::now() + time::Duration::from_secs(config.timeout),
+    }
+  }
+
+  /// Whether the core may exit, otherwise when to ask again at the latest.
+  fn step(&mut self, rtodo: &Rtodo) -> Result<(), time::Duration> {
+    let running: Vec<&Process> = rtodo
+      .works
+      .iter()
+      .flat_map(|work| work.running_processes.iter())
+      .collect();
+    if running.is_empty() {
+      return Ok(());
+    }
+    let now = Instant::now();
+    if now < self.deadline {
+      return Err(self.deadline - now);
+    }
+    let signal = match self.policy {
+      ShutdownPolicy::Detach => {
+        info!("Info: Leaving {} jobs running", running.len());
+        return Ok(());
       }
-      Err(err) => {
+      ShutdownPolicy::Terminate => {
+        self.policy = ShutdownPolicy::Kill;
+        Signal::SIGTERM
+      }
+      ShutdownPolicy::Kill => {
+        self.policy = ShutdownPolicy::Detach;
+        Signal::SIGKILL
+      }
+    };
+    info!("Info: Sending {} to {} running jobs", signal, running.len());
+    for process in running {
+      if let Err(err) = process.signal(signal) {
         error!(
-          "Error: Internal error: {}, line:{}, file: {}",
-          err,
-          line!(),
-          file!()
+          "Error: Failed to signal process {}, Err: {}",
+          process.pid, err
         );
-        return;
       }
     }
-    .executor_pid = {
-      #[cfg(target_family = "unix")]
-      nix::unistd::getpid().as_raw()
-    }
+    self.deadline = now + KILL_GRACE;
+    Err(KILL_GRACE)
   }
-  info!("Info: Starting exectutor");
+}
+
+/// Owns the state, everything else reaches it through `Command`s, so due works
+/// are never skipped for a busy lock and each command sees a consistent state.
+pub fn run_core(mut rtodo: Rtodo, commands: Receiver<Command>) {
+  info!("Info: Starting core");
+  let mut schedule = Schedule::default();
+  let mut shutdown: Option<Shutdown> = None;
+  let mut reporter = Reporter::default();
+  let mut reap_pending = false;
+  // Processes may have exited before the reaper started.
+  let mut command = Some(Command::ChildExited);
   loop {
-    thread::sleep(time::Duration::from_millis(100));
-    let works = {
-      &match rtodo_rwl.try_read() {
-        Ok(data) => {
-          match data.daemon_status {
-            RtodoDaemonStatus::Running => (),
-            RtodoDaemonStatus::Stopped => {
-              info!("Info: Stopping executor");
-              exit(0);
-            }
-          };
-          data
-        }
-        Err(err) => {
-          error!(
-            "Error: Internal error: {}, line:{}, file: {}",
-            err,
-            line!(),
-            file!()
-          );
-          continue;
-        }
+    health::beat(Loop::Core);
+    let mut changed = match command.take() {
+      None => false,
+      Some(Command::Read(read)) => {
+        read(&rtodo);
+        false
+      }
+      Some(Command::Write(write)) => {
+        write(&mut rtodo);
+        true
+      }
+      Some(Command::ChildExited) => {
+        reap_pending = true;
+        false
+      }
+      Some(Command::Stop) => {
+        rtodo.stop_daemon();
+        false
       }
-      .works
     };
-    for work_rwl in works.iter() {
-      let work = {
-        match work_rwl.try_read() {
-          Ok(data) => data,
-          Err(err) => {
-            error!(
-              "Error: Internal error: {}, line:{}, file: {}",
-              err,
-              line!(),
-              file!()
-            );
-            continue;
-          }
-        }
-        .clone()
-      };
-      //#[cfg(debug_assertions)]
-      //print!("{}", work_write_guard.entry.name);
-      match &work.entry.trigger {
-        Trigger::Timer(_) => {
-          if work.trigger_state.exec_time.clone().unwrap().is_up() {
-            match work.status {
-              Status::Running => {
-                let mut work_write_guard = match work_rwl.try_write() {
-                  Ok(data) => {
-                    #[cfg(debug_assertions)]
-                    info!(
-                      "Info: got write lock of works at line:{}, file: {}",
-                      line!(),
-                      file!()
-                    );
-                    data
-                  }
-                  Err(err) => {
-                    error!(
-                      "Error: Internal error: {}, line:{}, file: {}",
-                      err,
-                      line!(),
-                      file!()
-                    );
-                    continue;
-                  }
-                };
-                match work_write_guard.entry.do_if_running {
-                  DoIfRunning::Continue => (),
-                  DoIfRunning::StartNew => {
-                    match work_write_guard.start() {
-                      Ok(_) => (),
-                      Err(err) => {
-                        error!(
-                          "Error: Failed in start entry {}, Error Info: {}",
-                          work_write_guard.entry.name, err
-                        );
-                        work_write_guard.status = Status::Error;
-                        continue;
-                      }
-                    }
-                    ()
-                  }
-                  DoIfRunning::Stop => {
-                    match work_write_guard.stop() {
-                      Ok(_) => (),
-                      Err(err) => {
-                        error!(
-                          "Error: Failed in stop entry {}, Error Info: {}",
-                          work_write_guard.entry.name, err
-                        );
-                        work_write_guard.status = Status::Error;
-                        continue;
-                      }
-                    }
-                    ()
-                  }
-                  DoIfRunning::Restart => {
-                    match work_write_guard.restart() {
-                      Ok(_) => (),
-                      Err(err) => {
-                        error!(
-                          "Error: Failed in restart entry {}, Error Info: {}",
-                          work_write_guard.entry.name, err
-                        );
-                        work_write_guard.status = Status::Error;
-                        continue;
-                      }
-                    }
-                    ()
-                  }
-                }
-              }
-              Status::Paused => (),
-              Status::Pending => {
-                let mut work_write_guard = match work_rwl.try_write() {
-                  Ok(data) => {
-                    #[cfg(debug_assertions)]
-                    info!(
-                      "Info: got write lock of works at line:{}, file: {}",
-                      line!(),
-                      file!()
-                    );
-                    data
-                  }
-                  Err(err) => {
-                    error!(
-                      "Error: Internal error: {}, line:{}, file: {}",
-                      err,
-                      line!(),
-                      file!()
-                    );
-                    continue;
-                  }
-                };
-                match work_write_guard.start() {
-                  Ok(_) => (),
-                  Err(err) => {
-                    error!(
-                      "Error: Failed in start entry {}, Error Info: {}",
-                      work_write_guard.entry.name, err
-                    );
-                    work_write_guard.status = Status::Error;
-                    continue;
-                  }
-                }
-              }
-              Status::Error => (),
-            }
+    // Runs in a cgroup report their end through `cgroup.events`. Without one
+    // nothing tells when the rest of a process group exits, it isn't our
+    // child, so those are probed on each pass, at least every `IDLE_WAKEUP`.
+    if reap_pending {
+      let reaped = reap(&mut rtodo);
+      reap_pending = reaped.lingering;
+      changed |= reaped.finished;
+    }
+    reporter.report(&rtodo);
+    let next = match rtodo.is_stopping() {
+      true => {
+        let shutdown = shutdown.get_or_insert_with(|| Shutdown::begin(&mut rtodo));
+        match shutdown.step(&rtodo) {
+          Ok(_) => {
+            info!("Info: Stopping core");
+            return;
           }
+          Err(next) => Some(next),
         }
-        Trigger::None => (),
       }
-    }
-  }
-}
-
-pub fn start_checker(rtodo_rwl: Arc<RwLock<Rtodo>>) {
-  {
-    match rtodo_rwl.write() {
-      Ok(data) => {
-        #[cfg(debug_assertions)]
-        info!(
-          "Info: got write lock of works at line:{}, file: {}",
-          line!(),
-          file!()
-        );
-        data
+      false => {
+        schedule_due(&mut rtodo, &mut schedule, changed);
+        schedule.next_due(chrono::Local::now().timestamp_millis())
       }
-      Err(err) => {
-        error!(
-          "Error: Internal error: {}, line:{}, file: {}",
-          err,
-          line!(),
-          file!()
-        );
+    };
+    let timeout = next.map_or(IDLE_WAKEUP, |next| next.min(IDLE_WAKEUP));
+    command = match commands.recv_timeout(timeout) {
+      Ok(data) => Some(data),
+      Err(RecvTimeoutError::Timeout) => None,
+      Err(RecvTimeoutError::Disconnected) => {
+        info!("Info: Stopping core");
         return;
       }
+    };
+  }
+}
+
+/// Starts queued runs if anything `changed`, and fires the works that are due.
+fn schedule_due(rtodo: &mut Rtodo, schedule: &mut Schedule, changed: bool) {
+  let mut counts = rtodo.job_counts();
+  if changed {
+    schedule.rebuild(&rtodo.works);
+    // Runs end, limits change, runs get queued, any of it may let queued runs start.
+    rtodo.start_queued_runs(&mut counts);
+  }
+  let now = chrono::Local::now().timestamp();
+  let due: Vec<(i64, usize)> = iter::from_fn(|| schedule.pop_due(now)).collect();
+  for (due, index) in due {
+    if index >= rtodo.works.len() {
+      continue;
     }
-    .checker_pid = {
-      #[cfg(target_family = "unix")]
-      nix::unistd::getpid().as_raw()
+    fire(rtodo, index, &mut counts);
+    // A work still due at the same time, e.g. running and set to continue,
+    // waits for a change rather than spinning.
+    let work = &rtodo.works[index];
+    if scheduler::due_time(work).is_some_and(|next| next != due) {
+      schedule.push(work);
     }
   }
-  info!("Info: Starting checker");
-  loop {
-    thread::sleep(time::Duration::from_millis(100));
-    let works = {
-      &match rtodo_rwl.try_read() {
-        Ok(data) => data,
-        Err(err) => {
-          error!(
-            "Error: Internal error: {}, line:{}, file: {}",
-            err,
-            line!(),
-            file!()
-          );
-          continue;
-        }
+}
+
+/// Acts on the due work at `index` per its status and `DoIfRunning`.
+fn fire(rtodo: &mut Rtodo, index: usize, counts: &mut JobCounts) {
+  let work = &mut rtodo.works[index];
+  if work.pause_expired() {
+    // Through the config too, or the entry comes back paused after a restart.
+    let identifier = EntryIdentifier::Id(work.entry.id);
+    if let Err(err) = rtodo.resume_entry(&identifier) {
+      let work = &mut rtodo.works[index];
+      error!(
+        "Error: Failed to persist resuming entry {}, Error Info: {}",
+        work.entry.name, err
+      );
+      work.resume();
+    }
+    return;
+  }
+  if !work.trigger_state.is_up() {
+    return;
+  }
+  let (action, res) = match work.status {
+    Status::Running => match work.entry.do_if_running {
+      DoIfRunning::Continue => return,
+      DoIfRunning::StartNew => ("start", rtodo.start_or_queue(index, counts)),
+      DoIfRunning::Stop => ("stop", work.stop()),
+      DoIfRunning::Restart => ("restart", work.restart()),
+    },
+    Status::Pending => ("start", rtodo.start_or_queue(index, counts)),
+    Status::Paused | Status::Error => return,
+  };
+  if let Err(err) = res {
+    let work = &mut rtodo.works[index];
+    error!(
+      "Error: Failed in {} entry {}, Error Info: {}",
+      action, work.entry.name, err
+    );
+    work.set_status(Status::Error);
+  }
+}
+
+/// What `reap` found.
+struct Reaped {
+  /// Some runs ended and got recorded.
+  finished: bool,
+  /// Some run without a cgroup had its first process exit while others live
+  /// on, only probing tells when they are gone.
+  lingering: bool,
+}
+
+/// Collects exited processes of all works and records the runs with none
+/// left alive.
+fn reap(rtodo: &mut Rtodo) -> Reaped {
+  let mut reaped = Reaped {
+    finished: false,
+    lingering: false,
+  };
+  for work in rtodo.works.iter_mut() {
+    if work.running_processes.is_empty() {
+      continue;
+    }
+    let mut exited = Vec::new();
+    work.running_processes.retain_mut(|process| {
+      if process.exit.is_none() {
+        process.exit = reap_process(process.pid);
       }
-      .works
-    };
-    for work_rwl in works.iter() {
-      let work = {
-        match work_rwl.try_read() {
-          Ok(data) => data,
-          Err(err) => {
-            #[cfg(debug_assertions)]
-            error!(
-              "Error: Internal error: {}, line:{}, file: {}",
-              err,
-              line!(),
-              file!()
-            );
-            continue;
-          }
+      match &process.exit {
+        None => true,
+        Some(_) if process.is_lingering() => {
+          reaped.lingering |= process.cgroup.is_none();
+          true
         }
-        .clone()
-      };
-      for (_, thread) in work.running_processes.iter().enumerate() {
-        if !check_if_process_by_pid_alive(thread.pid) {
-          match work_rwl.try_write() {
-            Ok(data) => {
-              #[cfg(debug_assertions)]
-              info!(
-                "Info: got write lock of works at line:{}, file: {}",
-                line!(),
-                file!()
-              );
-              data
-            }
-            Err(err) => {
-              #[cfg(debug_assertions)]
-              error!(
-                "Error: Internal error: {}, line:{}, file: {}",
-                err,
-                line!(),
-                file!()
-              );
-              continue;
-            }
-          }
-          .status = Status::Pending;
+        Some(result) => {
+          exited.push((process.clone(), result.clone().err()));
+          false
         }
       }
+    });
+    reaped.finished |= !exited.is_empty();
+    for (process, error) in exited {
+      work.finish_run(process, error);
+    }
+    if work.running_processes.is_empty() {
+      if let Status::Running = work.status {
+        work.set_status(Status::Pending);
+      }
     }
   }
+  reaped
 }
 
-pub fn start_daemon(rtodo_rwl: RwLock<Rtodo>) -> Result<(), Box<dyn Error>> {
-  ctrlc::set_handler(|| exit(0)).unwrap();
-  let rtodo_rwl = Arc::new(rtodo_rwl);
-  let rtodo_rwl_move = rtodo_rwl.clone();
-  let server_thread = thread::spawn(move || start_server(rtodo_rwl_move));
-  let rtodo_rwl_move = rtodo_rwl.clone();
-  thread::spawn(move || start_executor(rtodo_rwl_move));
-  let rtodo_rwl_move = rtodo_rwl.clone();
-  thread::spawn(move || start_checker(rtodo_rwl_move));
-  match server_thread.join() {
+/// Tells the core whenever child processes exit or a run's cgroup changes.
+pub fn run_reaper(exits: RawFd, core: Sender<Command>) {
+  info!("Info: Starting reaper");
+  loop {
+    health::beat(Loop::Reaper);
+    if !scheduler::wait_for_child_exit(exits, cgroup::events_fd()) {
+      continue;
+    }
+    if core.send(Command::ChildExited).is_err() {
+      return;
+    }
+  }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+  let mut content = String::new();
+  file.read_to_string(&mut content).ok()?;
+  content.trim().parse().ok()
+}
+
+/// The pid of the daemon holding the lock of the PID file, if any.
+pub fn running_pid(path: &Path) -> Result<Option<u32>, Box<dyn Error>> {
+  let mut file = match File::open(path) {
+    Ok(file) => file,
+    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(format!("{:?}, {}", path, err).into()),
+  };
+  match flock(file.as_raw_fd(), FlockArg::LockSharedNonblock) {
+    // Left behind by a daemon that exited.
+    Ok(_) => Ok(None),
+    Err(nix::Error::EWOULDBLOCK) => Ok(Some(
+      read_pid(&mut file).ok_or(format!("{:?} is locked but holds no pid", path))?,
+    )),
+    Err(err) => Err(format!("{:?}, {}", path, err).into()),
+  }
+}
+
+/// Takes the lock of the PID file, then detaches if asked. Forks, so it must
+/// run while the process has a single thread.
+pub fn daemonize(
+  options: &DaemonOptions,
+  config: &Config,
+  conf_path: &Path,
+) -> Result<(), Box<dyn Error>> {
+  let path = config.pid_file_path(conf_path);
+  // Not truncated before it is locked, it may be another daemon's.
+  let mut file = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .mode(0o644)
+    .open(&path)
+    .map_err(|err| format!("Cannot open PID file {:?}, Err: {}", path, err))?;
+  match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
     Ok(_) => (),
-    Err(_) => {
-      error!("Error: Failed to join server thread");
+    Err(nix::Error::EWOULDBLOCK) => {
+      let pid = read_pid(&mut file).map_or(String::new(), |pid| format!(", pid {}", pid));
+      return Err(
+        format!(
+          "Another daemon is already running with this config{}, it holds the lock of {:?}",
+          pid, path
+        )
+        .into(),
+      );
     }
+    Err(err) => return Err(format!("Cannot lock PID file {:?}, Err: {}", path, err).into()),
+  }
+  if options.detach {
+    let log_file = match &options.log_file {
+      Some(path) => path.clone(),
+      None => conf_path.with_file_name("rtodo.log"),
+    };
+    detach(&log_file)?;
   }
+  // Left in place on exit, removing it could race with a daemon starting.
+  file.set_len(0)?;
+  file.write_all(format!("{}\n", process::id()).as_bytes())?;
+  let _ = PID_FILE.set(file);
   Ok(())
 }
+
+/// Double forks into a new session, with stdout and stderr going to `log_file`.
+/// Stays in the working directory, the config path may be relative to it.
+fn detach(log_file: &Path) -> Result<(), Box<dyn Error>> {
+  let log = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(log_file)
+    .map_err(|err| format!("Cannot open log file {:?}, Err: {}", log_file, err))?;
+  let null = File::open("/dev/null")?;
+  if let ForkResult::Parent { child } = unsafe { fork() }? {
+    let _ = waitpid(child, None);
+    info!("Info: Daemon detached, logging to {:?}", log_file);
+    exit(0);
+  }
+  setsid()?;
+  // No longer a session leader, so it can't get a controlling terminal.
+  if let ForkResult::Parent { .. } = unsafe { fork() }? {
+    exit(0);
+  }
+  dup2(null.as_raw_fd(), 0)?;
+  dup2(log.as_raw_fd(), 1)?;
+  dup2(log.as_raw_fd(), 2)?;
+  Ok(())
+}
+
+pub fn start_daemon(rtodo: Rtodo) -> Result<(), Box<dyn Error>> {
+  let listeners = systemd::init();
+  let (core, commands) = mpsc::channel();
+  let signalled = core.clone();
+  let mut stopping = false;
+  // SIGTERM and SIGINT, the server leaves them to us.
+  ctrlc::set_handler(move || {
+    if stopping {
+      info!("Info: Signalled again, exiting without waiting for jobs");
+      exit(1);
+    }
+    stopping = true;
+    let _ = signalled.send(Command::Stop);
+  })?;
+  match (&rtodo.config.cgroup, cgroup::delegated()) {
+    (Some(root), _) => cgroup::init(root)?,
+    (None, Some(root)) => match cgroup::init(&root) {
+      Ok(()) => info!("Info: Tracking runs in the delegated cgroup {:?}", root),
+      Err(err) => warn!(
+        "Warn: Cannot track runs in the delegated cgroup {:?}, Err: {}",
+        root, err
+      ),
+    },
+    (None, None) => (),
+  }
+  init_output_dir(&rtodo.output_dir_path())?;
+  health::start();
+  // Before any child is spawned, so that no exit goes unnoticed.
+  let exits = scheduler::watch_child_exits()?;
+  let core_move = core.clone();
+  let server_thread = thread::spawn(move || start_server(core_move, listeners));
+  let core_thread = thread::spawn(move || run_core(rtodo, commands));
+  let reaper_thread = thread::spawn(move || run_reaper(exits, core));
+  // A core that panicked took the state with it, so exit and leave the
+  // restart to the service manager.
+  loop {
+    thread::sleep(time::Duration::from_millis(500));
+    if core_thread.is_finished() {
+      if core_thread.join().is_err() {
+        error!("Error: The core thread died, jobs are no longer run, exiting");
+        exit(1);
+      }
+      // The core stopped, the response to `/api/stopDaemon` had the time
+      // above to go out.
+      info!("Info: Daemon stopped");
+      log::logger().flush();
+      exit(0);
+    }
+    if reaper_thread.is_finished() {
+      error!("Error: The reaper thread died, jobs are no longer run, exiting");
+      exit(1);
+    }
+    // Nothing takes commands without the server, not even stopDaemon, so
+    // leave the restart to the service manager too.
+    if server_thread.is_finished() && !STOPPING.load(Ordering::Relaxed) {
+      match server_thread.join() {
+        Ok(()) => error!("Error: The API server stopped, exiting"),
+        Err(_) => error!("Error: The API server died, exiting"),
+      }
+      exit(1);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::funcs::tests::{entry, rtodo};
+  use nix::sys::wait::{WaitPidFlag, WaitStatus};
+  use nix::unistd::Pid;
+  use std::io::{BufRead, BufReader};
+  use std::os::unix::process::CommandExt;
+
+  /// A running job in a process group of its own, as rtodo starts them. Left
+  /// for the test to reap with `waitpid`, like the daemon does.
+  #[allow(clippy::zombie_processes)]
+  fn job(rtodo: &mut Rtodo, script: &str) -> Pid {
+    let mut child = process::Command::new("/bin/sh")
+      .args(["-c", script])
+      .stdout(process::Stdio::piped())
+      .process_group(0)
+      .spawn()
+      .unwrap();
+    // Signals must not arrive before the job set up its traps.
+    let mut ready = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+      .read_line(&mut ready)
+      .unwrap();
+    let id = rtodo.works.len() as u32 + 1;
+    let mut work = Work::new(entry(id, &format!("job-{}", id)));
+    work
+      .running_processes
+      .push(Process::new(child.id(), id as u64, None, None));
+    rtodo.works.push(work);
+    Pid::from_raw(child.id() as i32)
+  }
+
+  #[test]
+  fn terminate_escalates_to_kill_after_the_grace_period() {
+    let mut rtodo = rtodo("shutdown", Vec::new());
+    let polite = job(&mut rtodo, "echo ready; exec sleep 30");
+    let stubborn = job(&mut rtodo, "trap '' TERM; echo ready; exec sleep 30");
+    let mut shutdown = Shutdown {
+      policy: ShutdownPolicy::Terminate,
+      deadline: Instant::now() + time::Duration::from_secs(60),
+    };
+    assert!(shutdown.step(&rtodo).unwrap_err() > time::Duration::from_secs(59));
+    assert_eq!(
+      waitpid(polite, Some(WaitPidFlag::WNOHANG)),
+      Ok(WaitStatus::StillAlive)
+    );
+    shutdown.deadline = Instant::now();
+    assert_eq!(shutdown.step(&rtodo), Err(KILL_GRACE));
+    assert_eq!(shutdown.policy, ShutdownPolicy::Kill);
+    assert_eq!(
+      waitpid(polite, None),
+      Ok(WaitStatus::Signaled(polite, Signal::SIGTERM, false))
+    );
+    thread::sleep(time::Duration::from_millis(100));
+    assert_eq!(
+      waitpid(stubborn, Some(WaitPidFlag::WNOHANG)),
+      Ok(WaitStatus::StillAlive)
+    );
+    rtodo.works.remove(0);
+    shutdown.deadline = Instant::now();
+    assert_eq!(shutdown.step(&rtodo), Err(KILL_GRACE));
+    assert_eq!(shutdown.policy, ShutdownPolicy::Detach);
+    assert_eq!(
+      waitpid(stubborn, None),
+      Ok(WaitStatus::Signaled(stubborn, Signal::SIGKILL, false))
+    );
+    shutdown.deadline = Instant::now();
+    assert_eq!(shutdown.step(&rtodo), Ok(()));
+  }
+}
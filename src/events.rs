@@ -0,0 +1,150 @@
+use crate::types::*;
+use crate::utils::*;
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use futures_util::{stream, StreamExt};
+use log::warn;
+use std::sync::OnceLock;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tokio::time::{sleep, Duration};
+
+/// Events kept for slow subscribers before they start missing some.
+const BUS_CAPACITY: usize = 256;
+/// Idle SSE connections get a comment this often so proxies keep them open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+fn bus() -> &'static Sender<Event> {
+  static BUS: OnceLock<Sender<Event>> = OnceLock::new();
+  BUS.get_or_init(|| broadcast::channel(BUS_CAPACITY).0)
+}
+
+pub fn publish(event: Event) {
+  // Sending only fails when nobody is subscribed.
+  let _ = bus().send(event);
+}
+
+/// All events from here on, for parts of the daemon waiting on one.
+pub fn listen() -> Receiver<Event> {
+  bus().subscribe()
+}
+
+async fn subscribe(
+  req: &HttpRequest,
+  query: &TokenQuery,
+  state: &RS,
+) -> Result<(Receiver<Event>, Grant), ApiError> {
+  let token = api_token(req, query.token.as_deref());
+  let grant = state
+    .read(move |rtodo| check_request_token(token.as_deref(), rtodo, TokenScope::Read))
+    .await??;
+  Ok((bus().subscribe(), grant))
+}
+
+async fn next_event(
+  receiver: &mut Receiver<Event>,
+  filter: &EntryFilter,
+  grant: &Grant,
+) -> Option<Event> {
+  loop {
+    match receiver.recv().await {
+      Ok(event) if filter.matches(&event) && grant.covers_event(&event) => return Some(event),
+      Ok(_) => (),
+      Err(RecvError::Lagged(count)) => {
+        warn!(
+          "Warn: Event subscriber lagged behind, skipped {} events",
+          count
+        )
+      }
+      Err(RecvError::Closed) => return None,
+    }
+  }
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/events",
+  params(EntryFilter, TokenQuery),
+  responses(
+    (status = 200, description = "Server-sent events, each `data:` line is a JSON Event", body = Event, content_type = "text/event-stream"),
+    (status = 401, description = "Missing or invalid token", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+pub async fn sse(
+  req: HttpRequest,
+  filter: web::Query<EntryFilter>,
+  query: web::Query<TokenQuery>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let (receiver, grant) = subscribe(&req, &query, &state).await?;
+  let stream = stream::unfold(
+    (receiver, filter.into_inner(), grant),
+    |(mut receiver, filter, grant)| async move {
+      let chunk = tokio::select! {
+        event = next_event(&mut receiver, &filter, &grant) => {
+          format!("data: {}\n\n", serde_json::to_string(&event?).ok()?)
+        }
+        _ = sleep(KEEP_ALIVE) => String::from(":\n\n"),
+      };
+      Some((
+        Ok::<_, actix_web::Error>(web::Bytes::from(chunk)),
+        (receiver, filter, grant),
+      ))
+    },
+  );
+  Ok(
+    HttpResponse::Ok()
+      .content_type("text/event-stream")
+      .insert_header((header::CACHE_CONTROL, "no-cache"))
+      .streaming(stream),
+  )
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/events/ws",
+  params(EntryFilter, TokenQuery),
+  responses(
+    (status = 101, description = "WebSocket upgrade, each text message is a JSON Event"),
+    (status = 401, description = "Missing or invalid token", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+pub async fn websocket(
+  req: HttpRequest,
+  body: web::Payload,
+  filter: web::Query<EntryFilter>,
+  query: web::Query<TokenQuery>,
+  state: RS,
+) -> Result<HttpResponse, actix_web::Error> {
+  let (mut receiver, grant) = subscribe(&req, &query, &state).await?;
+  let (res, mut session, mut messages) = actix_ws::handle(&req, body)?;
+  let filter = filter.into_inner();
+  actix_web::rt::spawn(async move {
+    loop {
+      tokio::select! {
+        event = next_event(&mut receiver, &filter, &grant) => {
+          let text = match event.map(|event| serde_json::to_string(&event)) {
+            Some(Ok(text)) => text,
+            _ => break,
+          };
+          if session.text(text).await.is_err() {
+            return;
+          }
+        }
+        message = messages.next() => match message {
+          Some(Ok(Message::Ping(bytes))) => {
+            if session.pong(&bytes).await.is_err() {
+              return;
+            }
+          }
+          Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+          Some(Ok(_)) => (),
+        },
+      }
+    }
+    let _ = session.close(None).await;
+  });
+  Ok(res)
+}
@@ -1,19 +1,39 @@
+use crate::cgroup;
 use crate::daemon;
+use crate::events;
+use crate::metrics;
+use crate::sandbox;
+use crate::systemd;
 use crate::types::*;
 use crate::utils::*;
+use actix_web::{http::header, http::StatusCode, HttpRequest, HttpResponse, ResponseError};
 use chrono::TimeZone;
 use chrono::{Datelike, Timelike};
-use log::{error, info};
-use std::collections::HashMap;
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{setsid, Pid};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
 use std::ops;
-use std::path::PathBuf;
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::RwLock;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
 use sysinfo::{SystemExt, UserExt};
+use tokio::sync::oneshot;
 
 impl Operation {
   pub fn from_args(args: &[String]) -> Result<Operation, Box<dyn Error>> {
@@ -28,53 +48,65 @@ impl Operation {
           args,
           Trigger::from_args(args),
           Logger::from_args(args),
-          Action::from_args(args),
+          Action::from_args(args)?,
           DoIfRunning::from_args(args),
           Status::from_args(args),
         )?;
-        operation = Operation::Add(entry);
+        operation = Operation::Add(Box::new(entry));
       }
       "delete" => {
         if check_if_help_in_args(args) {
           return Ok(Operation::Help(Some(OperationType::Delete)));
         }
-        if let Ok(id) = args[2].parse::<u32>() {
-          operation = Operation::Delete(EntryIdentifier::Id(id));
-        } else if let Ok(name) = args[2].parse::<String>() {
-          operation = Operation::Delete(EntryIdentifier::Name(name));
-        } else {
-          return Err("Invalid entry identifier".into());
-        }
+        operation = Operation::Delete(EntryIdentifier::from_args(args)?);
       }
-      "start" => {
+      "start" | "run" => {
         if check_if_help_in_args(args) {
           return Ok(Operation::Help(Some(OperationType::Start)));
         }
-        if let Ok(id) = args[2].parse::<u32>() {
-          operation = Operation::Start(EntryIdentifier::Id(id));
-        } else if let Ok(name) = args[2].parse::<String>() {
-          operation = Operation::Start(EntryIdentifier::Name(name));
-        } else {
-          return Err("Invalid entry identifier".into());
-        }
+        operation = Operation::Start(RunEntry::from_args(args)?);
       }
       "pause" => {
         if check_if_help_in_args(args) {
           return Ok(Operation::Help(Some(OperationType::Pause)));
         }
-        if let Ok(id) = args[2].parse::<u32>() {
-          operation = Operation::Pause(EntryIdentifier::Id(id));
-        } else if let Ok(name) = args[2].parse::<String>() {
-          operation = Operation::Pause(EntryIdentifier::Name(name));
-        } else {
-          return Err("Invalid entry identifier".into());
+        operation = Operation::Pause(PauseEntry::from_args(args)?);
+      }
+      "resume" => {
+        if check_if_help_in_args(args) {
+          return Ok(Operation::Help(Some(OperationType::Resume)));
+        }
+        operation = Operation::Resume(EntryIdentifier::from_args(args)?);
+      }
+      "skip" => {
+        if check_if_help_in_args(args) {
+          return Ok(Operation::Help(Some(OperationType::Skip)));
+        }
+        operation = Operation::Skip(EntryIdentifier::from_args(args)?);
+      }
+      "watch" => {
+        if check_if_help_in_args(args) {
+          return Ok(Operation::Help(Some(OperationType::Watch)));
+        }
+        operation = Operation::Watch(args.get(2).filter(|arg| !arg.starts_with("--")).cloned());
+      }
+      "logs" => {
+        if check_if_help_in_args(args) {
+          return Ok(Operation::Help(Some(OperationType::Logs)));
         }
+        operation = Operation::Logs(LogsEntry::from_args(args)?);
+      }
+      "token" => {
+        if check_if_help_in_args(args) {
+          return Ok(Operation::Help(Some(OperationType::Token)));
+        }
+        operation = Operation::Token(TokenOperation::from_args(args)?);
       }
       "start-daemon" => {
         if check_if_help_in_args(args) {
           return Ok(Operation::Help(Some(OperationType::StartDaemon)));
         }
-        operation = Operation::StartDaemon();
+        operation = Operation::StartDaemon(DaemonOptions::from_args(args)?);
       }
       "stop-daemon" => {
         if check_if_help_in_args(args) {
@@ -82,6 +114,21 @@ impl Operation {
         }
         operation = Operation::StopDaemon();
       }
+      "status" => {
+        if check_if_help_in_args(args) {
+          return Ok(Operation::Help(Some(OperationType::Status)));
+        }
+        operation = Operation::Status();
+      }
+      "systemd-unit" => {
+        if check_if_help_in_args(args) {
+          return Ok(Operation::Help(Some(OperationType::SystemdUnit)));
+        }
+        operation = Operation::SystemdUnit(match args.iter().any(|arg| arg == "--socket") {
+          true => UnitKind::Socket,
+          false => UnitKind::Service,
+        });
+      }
       "list" => {
         if check_if_help_in_args(args) {
           return Ok(Operation::Help(Some(OperationType::List)));
@@ -92,13 +139,7 @@ impl Operation {
         if check_if_help_in_args(args) {
           return Ok(Operation::Help(Some(OperationType::Detail)));
         }
-        if let Ok(id) = args[2].parse::<u32>() {
-          operation = Operation::Detail(EntryIdentifier::Id(id));
-        } else if let Ok(name) = args[2].parse::<String>() {
-          operation = Operation::Detail(EntryIdentifier::Name(name));
-        } else {
-          return Err("Invalid entry identifier".into());
-        }
+        operation = Operation::Detail(EntryIdentifier::from_args(args)?);
       }
       "help" => {
         if check_if_help_in_args(args) {
@@ -121,10 +162,7 @@ impl Operation {
     match self {
       Operation::Add(entry) => match rtodo
         .rcli
-        .post(format!(
-          "http://{}/api/addEntries",
-          rtodo.config.address.clone()
-        ))
+        .post(format!("{}/addEntries", rtodo.api_url))
         .json(&ReqCommonData {
           token: rtodo.config.token.clone(),
           data: Some(vec![&entry]),
@@ -162,10 +200,121 @@ impl Operation {
           );
         }
       },
-      Operation::StartDaemon() => match daemon::start_daemon(RwLock::new(rtodo)) {
-        Ok(_) => {
-          return;
+      Operation::Start(run) => match rtodo.call_api::<_, serde_json::Value>("runEntry", run) {
+        Ok(res) if res.code == 200 => match serde_json::from_value(res.data) {
+          Ok(RunOutcome::Started) => info!("Success: run entry {} successfully", run.entry),
+          Ok(RunOutcome::Queued { reason }) => {
+            info!("Info: Queued run of entry {}, {}", run.entry, reason)
+          }
+          Ok(RunOutcome::Stopped) => {
+            info!("Info: Stopped entry {} as it was running", run.entry)
+          }
+          Err(err) => error!("Error: Failed to run entry, invalid response, Err: {}", err),
+        },
+        Ok(res) => error!(
+          "Error: Failed to run entry, {}",
+          res.data.as_str().unwrap_or_default()
+        ),
+        Err(err) => error!(
+          "Error: Failed to run entry, cannot connect to daemon, Addr: {}, Err: {}",
+          rtodo.config.address, err
+        ),
+      },
+      Operation::Pause(pause) => report_entry_operation(
+        &rtodo,
+        "pause",
+        &pause.entry,
+        rtodo.call_api("pauseEntry", pause),
+      ),
+      Operation::Resume(identifier) => report_entry_operation(
+        &rtodo,
+        "resume",
+        identifier,
+        rtodo.call_api("resumeEntry", identifier),
+      ),
+      Operation::Skip(identifier) => report_entry_operation(
+        &rtodo,
+        "skip",
+        identifier,
+        rtodo.call_api("skipEntry", identifier),
+      ),
+      Operation::Watch(entry) => match rtodo.watch_events(entry.as_deref()) {
+        Ok(_) => info!("Info: Event stream closed by daemon"),
+        Err(err) => error!(
+          "Error: Failed to watch events, Addr: {}, Err: {}",
+          rtodo.config.address, err
+        ),
+      },
+      Operation::Logs(logs) => match rtodo.print_logs(logs) {
+        Ok(_) => (),
+        Err(err) => error!(
+          "Error: Failed to show logs of entry {}, Addr: {}, Err: {}",
+          logs.entry, rtodo.config.address, err
+        ),
+      },
+      Operation::Token(TokenOperation::Create(new)) => {
+        match rtodo.call_api::<_, String>("createToken", new) {
+          Ok(res) if res.code == 200 => {
+            info!("Success: Created token {}, it is not shown again", new.name);
+            println!("{}", res.data);
+          }
+          Ok(res) => error!("Error: Failed to create token, {}", res.data),
+          Err(err) => error!(
+            "Error: Failed to create token, cannot connect to daemon, Addr: {}, Err: {}",
+            rtodo.config.address, err
+          ),
+        }
+      }
+      Operation::Token(TokenOperation::List) => {
+        match rtodo.call_api::<_, serde_json::Value>("listTokens", ()) {
+          Ok(res) if res.code == 200 => match serde_json::from_value::<Vec<TokenInfo>>(res.data) {
+            Ok(tokens) => tokens.iter().for_each(|token| println!("{}", token)),
+            Err(err) => error!("Error: Failed to list tokens, Err: {}", err),
+          },
+          Ok(res) => error!("Error: Failed to list tokens, {}", res.data),
+          Err(err) => error!(
+            "Error: Failed to list tokens, cannot connect to daemon, Addr: {}, Err: {}",
+            rtodo.config.address, err
+          ),
+        }
+      }
+      Operation::Token(TokenOperation::Revoke(name)) => {
+        match rtodo.call_api::<_, String>("revokeToken", name) {
+          Ok(res) if res.code == 200 => info!("Success: Revoked token {}", name),
+          Ok(res) => error!("Error: Failed to revoke token, {}", res.data),
+          Err(err) => error!(
+            "Error: Failed to revoke token, cannot connect to daemon, Addr: {}, Err: {}",
+            rtodo.config.address, err
+          ),
+        }
+      }
+      Operation::Status() => match daemon::running_pid(&rtodo.pid_file_path()) {
+        Ok(None) => {
+          println!("rtodo is not running");
+          process::exit(3);
+        }
+        Ok(Some(pid)) => match rtodo.get_status() {
+          Ok(info) => println!("rtodo is {}", info),
+          Err(err) => {
+            println!(
+              "rtodo is running, pid {}, but does not answer: {}",
+              pid, err
+            );
+            process::exit(1);
+          }
+        },
+        Err(err) => {
+          error!("Error: Failed to read the PID file, Err: {}", err);
+          process::exit(4);
         }
+      },
+      Operation::SystemdUnit(UnitKind::Service) => match systemd::service_unit(&rtodo) {
+        Ok(unit) => print!("{}", unit),
+        Err(err) => error!("Error: Failed to generate the service unit, Err: {}", err),
+      },
+      Operation::SystemdUnit(UnitKind::Socket) => print!("{}", systemd::socket_unit(&rtodo)),
+      Operation::StartDaemon(_) => match daemon::start_daemon(rtodo) {
+        Ok(_) => {}
         Err(err) => {
           panic!("{}", err);
         }
@@ -175,6 +324,173 @@ impl Operation {
   }
 }
 
+fn report_entry_operation(
+  rtodo: &Rtodo,
+  operation: &str,
+  identifier: &EntryIdentifier,
+  res: Result<ResCommonData<String>, Box<dyn Error>>,
+) {
+  match res {
+    Ok(res) => {
+      if res.code == 200 {
+        info!("Success: {} entry {} successfully", operation, identifier);
+      } else {
+        error!("Error: Failed to {} entry, {}", operation, res.data);
+      }
+    }
+    Err(err) => {
+      error!(
+        "Error: Failed to {} entry, cannot connect to daemon, Addr: {}, Err: {}",
+        operation, rtodo.config.address, err
+      );
+    }
+  }
+}
+
+impl EntryIdentifier {
+  pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+    let arg = args.get(2).ok_or("Invalid entry identifier")?;
+    Ok(Self::parse(arg))
+  }
+
+  pub fn parse(arg: &str) -> Self {
+    match arg.parse::<u32>() {
+      Ok(id) => Self::Id(id),
+      Err(_) => Self::Name(arg.to_string()),
+    }
+  }
+
+  pub fn matches(&self, entry: &Entry) -> bool {
+    match self {
+      Self::Id(id) => entry.id == *id,
+      Self::Name(name) => entry.name == *name,
+    }
+  }
+}
+
+impl fmt::Display for EntryIdentifier {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Id(id) => write!(f, "{}", id),
+      Self::Name(name) => write!(f, "{}", name),
+    }
+  }
+}
+
+impl PauseEntry {
+  pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+    let mut pause = Self {
+      entry: EntryIdentifier::from_args(args)?,
+      until: None,
+    };
+    for (index, arg) in args.iter().enumerate() {
+      if arg == "--until" {
+        let until = args.get(index + 1).ok_or("Invalid argument")?;
+        pause.until =
+          Some(DateTime::parse(until).ok_or("Invalid datetime, expected YYYY-MM-DD HH:MM:SS")?);
+      }
+    }
+    Ok(pause)
+  }
+}
+
+impl LogsEntry {
+  pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+    let mut logs = Self {
+      entry: EntryIdentifier::from_args(args)?,
+      run: None,
+      follow: false,
+    };
+    for (index, arg) in args.iter().enumerate() {
+      match arg.as_str() {
+        "-f" | "--follow" => logs.follow = true,
+        "--run" => logs.run = Some(garg(args, index + 1).ok_or("Invalid run id")?),
+        _ => (),
+      }
+    }
+    Ok(logs)
+  }
+}
+
+impl DaemonOptions {
+  pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+    let mut options = Self {
+      detach: false,
+      log_file: None,
+    };
+    for (index, arg) in args.iter().enumerate() {
+      match arg.as_str() {
+        "--detach" => options.detach = true,
+        "--log-file" => options.log_file = Some(garg(args, index + 1).ok_or("Invalid log file")?),
+        _ => (),
+      }
+    }
+    Ok(options)
+  }
+}
+
+impl TokenOperation {
+  pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+    let err = "Invalid argument";
+    let name = args
+      .get(3)
+      .filter(|arg| !arg.starts_with("--"))
+      .ok_or("Invalid token name")
+      .cloned();
+    match args.get(2).map(String::as_str) {
+      Some("create") => {
+        let mut new = NewToken {
+          name: name?,
+          scope: TokenScope::Read,
+          entries: Vec::new(),
+          tags: Vec::new(),
+        };
+        for (index, arg) in args.iter().enumerate() {
+          match arg.as_str() {
+            "--scope" => {
+              new.scope =
+                garg(args, index + 1).ok_or("Invalid scope, expected read, operate or admin")?
+            }
+            "--entry" => new.entries.push(garg(args, index + 1).ok_or(err)?),
+            "--tag" => new.tags.push(garg(args, index + 1).ok_or(err)?),
+            _ => (),
+          }
+        }
+        Ok(Self::Create(new))
+      }
+      Some("list") => Ok(Self::List),
+      Some("revoke") => Ok(Self::Revoke(name?)),
+      _ => Err("Invalid token operation, expected create, list or revoke".into()),
+    }
+  }
+}
+
+impl RunEntry {
+  pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+    let mut run = Self {
+      entry: EntryIdentifier::from_args(args)?,
+      args: None,
+      env: None,
+    };
+    for (index, arg) in args.iter().enumerate() {
+      match arg.as_str() {
+        "--args" => {
+          run.args = garg::<String>(args, index + 1)
+            .map(|data| split_args(&data))
+            .transpose()?
+        }
+        "--env" => {
+          run.env = garg::<String>(args, index + 1)
+            .map(|data| env_pairs(&data))
+            .transpose()?
+        }
+        _ => (),
+      }
+    }
+    Ok(run)
+  }
+}
+
 impl Entry {
   pub fn new(
     trigger: Trigger,
@@ -192,6 +508,13 @@ impl Entry {
       status,
       do_if_running,
       enabled: true,
+      jitter: 0,
+      splay: 0,
+      paused_until: None,
+      max_instances: None,
+      overflow_policy: OverflowPolicy::default(),
+      locks: Vec::new(),
+      tags: Vec::new(),
     }
   }
   pub fn from_args(
@@ -214,14 +537,41 @@ impl Entry {
         "--disable" => {
           entry.enabled = false;
         }
+        "--jitter" => entry.jitter = garg(args, index + 1).ok_or(err)?,
+        "--splay" => entry.splay = garg(args, index + 1).ok_or(err)?,
+        "--max-instances" => entry.max_instances = Some(garg(args, index + 1).ok_or(err)?),
+        "--drop-excess" => entry.overflow_policy = OverflowPolicy::Drop,
+        "--lock" => entry.locks.push(garg(args, index + 1).ok_or(err)?),
+        "--tag" => entry.tags.push(garg(args, index + 1).ok_or(err)?),
         _ => (),
       }
     }
     Ok(entry)
   }
+
+  pub fn schedule_delay(&self) -> u32 {
+    let splay = match self.splay {
+      0 => 0,
+      splay => (stable_hash(&format!("{}:{}", host_name(), self.id)) % (splay as u64 + 1)) as u32,
+    };
+    let jitter = match self.jitter {
+      0 => 0,
+      jitter => rand::thread_rng().gen_range(0..=jitter),
+    };
+    splay + jitter
+  }
 }
 
 impl Logger {
+  /// Where the output of run `run_id` goes, `None` discards it.
+  pub fn output_file(&self, run_id: u64) -> Option<PathBuf> {
+    match self {
+      Self::File(path) => Some(PathBuf::from(path)),
+      Self::Default => Some(output_dir().join(format!("{}.log", run_id))),
+      Self::Off => None,
+    }
+  }
+
   pub fn from_args(args: &[String]) -> Self {
     let mut logger = Self::Default;
     for (index, arg) in args.iter().enumerate() {
@@ -247,26 +597,20 @@ impl Config {
   }
 
   pub fn delete_entry(&mut self, identifier: &EntryIdentifier) {
-    let mut indexes = vec![];
-    let mut offset = 0;
-    for (index, entry) in self.entries.iter().enumerate() {
-      match identifier.clone() {
-        EntryIdentifier::Id(id) => {
-          if entry.id == id {
-            indexes.push(index);
-          }
-        }
-        EntryIdentifier::Name(name) => {
-          if entry.name == name {
-            indexes.push(index);
-          }
-        }
-      }
+    self.entries.retain(|entry| !identifier.matches(entry));
+  }
+
+  pub fn pid_file_path(&self, conf_path: &Path) -> PathBuf {
+    match &self.pid_file {
+      Some(path) => path.clone(),
+      None => conf_path.with_file_name("rtodo.pid"),
     }
-    indexes.sort();
-    for i in indexes {
-      self.entries.remove(i - offset);
-      offset += 1;
+  }
+
+  pub fn output_dir_path(&self, conf_path: &Path) -> PathBuf {
+    match &self.output_dir {
+      Some(path) => path.clone(),
+      None => conf_path.with_file_name("runs"),
     }
   }
 
@@ -286,20 +630,173 @@ impl Config {
   }
 }
 
+impl Config {
+  pub fn default_max_queued_jobs() -> usize {
+    64
+  }
+
+  pub fn default_tcp_enabled() -> bool {
+    true
+  }
+
+  pub fn default_socket_path() -> Option<PathBuf> {
+    Some(PathBuf::from("/run/rtodo/rtodo.sock"))
+  }
+
+  pub fn default_socket_mode() -> String {
+    String::from("660")
+  }
+
+  pub fn lock_capacity(&self, name: &str) -> usize {
+    self.locks.get(name).copied().unwrap_or(1) as usize
+  }
+
+  pub fn queue_run(
+    &self,
+    queue_seq: &AtomicU64,
+    work: &mut Work,
+    run: RunEntry,
+    counts: &JobCounts,
+    reason: &str,
+  ) -> Result<(), Box<dyn Error>> {
+    match work.entry.overflow_policy {
+      OverflowPolicy::Queue if counts.queued < self.max_queued_jobs => {
+        work.queue(run, queue_seq.fetch_add(1, Ordering::Relaxed));
+        Ok(())
+      }
+      OverflowPolicy::Queue => Err(
+        format!(
+          "Run queue is full, dropped run of entry {}",
+          work.entry.name
+        )
+        .into(),
+      ),
+      OverflowPolicy::Drop => {
+        Err(format!("Dropped run of entry {}, {}", work.entry.name, reason).into())
+      }
+    }
+  }
+
+  pub fn check_limits(&self, work: &Work, counts: &JobCounts) -> Result<(), String> {
+    if let Some(max) = work.entry.max_instances {
+      if work.running_processes.len() >= max as usize {
+        return Err(format!("entry reached max_instances {}", max));
+      }
+    }
+    if let Some(max) = self.max_concurrent_jobs {
+      if counts.running >= max as usize {
+        return Err(format!("max_concurrent_jobs {} reached", max));
+      }
+    }
+    for lock in work.entry.locks.iter() {
+      if counts.locks.get(lock).copied().unwrap_or(0) >= self.lock_capacity(lock) {
+        return Err(format!("lock {} is held", lock));
+      }
+    }
+    Ok(())
+  }
+}
+
+impl SocketAccess {
+  /// Root and the daemon's own user are always allowed.
+  pub fn allows(&self, peer: &SocketPeer) -> bool {
+    match &peer.0 {
+      Some(cred) => {
+        cred.uid() == 0
+          || cred.uid() == nix::unistd::getuid().as_raw()
+          || self.uids.contains(&cred.uid())
+          || self.gids.contains(&cred.gid())
+      }
+      None => false,
+    }
+  }
+}
+
+impl JobCounts {
+  pub fn add(&mut self, work: &Work, processes: usize) {
+    self.running += processes;
+    for lock in work.entry.locks.iter() {
+      *self.locks.entry(lock.clone()).or_insert(0) += processes;
+    }
+  }
+}
+
 impl Default for Config {
   fn default() -> Self {
     Self {
       entries: Vec::new(),
       address: String::from("0.0.0.0:6472"),
       token: generate_token(),
+      max_concurrent_jobs: None,
+      max_queued_jobs: Self::default_max_queued_jobs(),
+      locks: HashMap::new(),
+      tcp_enabled: Self::default_tcp_enabled(),
+      socket_path: Self::default_socket_path(),
+      socket_mode: Self::default_socket_mode(),
+      socket_access: SocketAccess::default(),
+      tls: None,
+      tokens: Vec::new(),
+      audit: AuditConfig::default(),
+      metrics: MetricsConfig::default(),
+      shutdown: ShutdownConfig::default(),
+      pid_file: None,
+      output_dir: None,
+      cgroup: None,
+    }
+  }
+}
+
+impl AuditConfig {
+  pub fn default_enabled() -> bool {
+    true
+  }
+
+  pub fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+  }
+
+  pub fn default_keep() -> u32 {
+    5
+  }
+}
+
+impl ShutdownConfig {
+  pub fn default_timeout() -> u64 {
+    30
+  }
+}
+
+impl Default for ShutdownConfig {
+  fn default() -> Self {
+    Self {
+      timeout: Self::default_timeout(),
+      on_timeout: ShutdownPolicy::default(),
+    }
+  }
+}
+
+impl Default for AuditConfig {
+  fn default() -> Self {
+    Self {
+      enabled: Self::default_enabled(),
+      path: None,
+      max_size: Self::default_max_size(),
+      keep: Self::default_keep(),
     }
   }
 }
 
 impl Rtodo {
   pub fn add_entry(&mut self, entry: Entry) -> Result<(), Box<dyn Error>> {
-    self.config.add_entry(entry, self.cur_entry_id + 1);
+    self.cur_entry_id += 1;
+    self.config.add_entry(entry, self.cur_entry_id);
     self.write_conf()?;
+    if let Some(entry) = self.config.entries.last() {
+      events::publish(Event::new(EventKind::EntryAdded, Some(entry)));
+      if entry.enabled {
+        self.works.push(Work::new(entry.clone()));
+      }
+    }
     Ok(())
   }
 
@@ -318,60 +815,988 @@ impl Rtodo {
     self.config.token.as_str()
   }
 
+  pub fn pid_file_path(&self) -> PathBuf {
+    self.config.pid_file_path(Path::new(&self.conf_path))
+  }
+
+  pub fn output_dir_path(&self) -> PathBuf {
+    self.config.output_dir_path(Path::new(&self.conf_path))
+  }
+
+  pub fn audit_path(&self) -> PathBuf {
+    match &self.config.audit.path {
+      Some(path) => path.clone(),
+      None => Path::new(&self.conf_path).with_file_name("audit.log"),
+    }
+  }
+
+  /// Looks up a token, the shared `token` of the config being an admin token
+  /// for all entries.
+  pub fn authenticate(&self, token: &str) -> Option<Grant> {
+    if constant_time_eq(token, self.get_token()) {
+      return Some(Grant {
+        name: String::from(DEFAULT_TOKEN_NAME),
+        scope: TokenScope::Admin,
+        entries: Vec::new(),
+        tags: Vec::new(),
+      });
+    }
+    self
+      .config
+      .tokens
+      .iter()
+      .find(|api_token| api_token.verify(token))
+      .map(Grant::from)
+  }
+
+  pub fn authorize(&self, token: &str, scope: TokenScope) -> Result<Grant, String> {
+    let grant = self.authenticate(token).ok_or("Invalid token")?;
+    grant.require(scope)?;
+    Ok(grant)
+  }
+
+  /// Checks an operation on an entry, unknown entries are left for the
+  /// operation itself to report.
+  pub fn check_access(
+    &self,
+    grant: &Grant,
+    scope: TokenScope,
+    identifier: &EntryIdentifier,
+  ) -> Result<(), String> {
+    match self.find_entry(identifier) {
+      Some(entry) => grant.require_entry(scope, entry),
+      None => grant.require(scope),
+    }
+  }
+
+  /// Entries deleted since are only covered by unrestricted tokens.
+  pub fn covers(&self, grant: &Grant, id: u32) -> bool {
+    !grant.is_restricted()
+      || self
+        .find_entry(&EntryIdentifier::Id(id))
+        .is_some_and(|entry| grant.covers_entry(entry))
+  }
+
+  /// Returns the new token, only its hash is kept.
+  pub fn create_token(&mut self, new: NewToken) -> Result<String, Box<dyn Error>> {
+    if new.name == DEFAULT_TOKEN_NAME {
+      return Err(
+        format!(
+          "Token name {} is reserved for the token of the config",
+          new.name
+        )
+        .into(),
+      );
+    }
+    if self
+      .config
+      .tokens
+      .iter()
+      .any(|token| token.name == new.name)
+    {
+      return Err(format!("Token {} already exists", new.name).into());
+    }
+    let (api_token, token) = ApiToken::generate(new);
+    self.config.tokens.push(api_token);
+    self.write_conf()?;
+    Ok(token)
+  }
+
+  pub fn list_tokens(&self) -> Vec<TokenInfo> {
+    self.config.tokens.iter().map(TokenInfo::from).collect()
+  }
+
+  pub fn revoke_token(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+    let count = self.config.tokens.len();
+    self.config.tokens.retain(|token| token.name != name);
+    if self.config.tokens.len() == count {
+      return Err(format!("Token {} not found", name).into());
+    }
+    self.write_conf()
+  }
+
   pub fn init_works(&mut self) -> Result<(), Box<dyn Error>> {
     for entry in self.get_entries() {
       if !entry.enabled {
         continue;
       }
-      self.works.push(RwLock::new(Work {
-        status: entry.status,
-        entry: entry.clone(),
-        trigger_state: TriggerState::from_entry(&entry),
-        running_processes: Vec::new(),
-      }))
+      self.works.push(Work::new(entry))
     }
     Ok(())
   }
 
+  pub fn find_entry(&self, identifier: &EntryIdentifier) -> Option<&Entry> {
+    self
+      .config
+      .entries
+      .iter()
+      .find(|entry| identifier.matches(entry))
+  }
+
+  pub fn find_work(&mut self, identifier: &EntryIdentifier) -> Option<&mut Work> {
+    self
+      .works
+      .iter_mut()
+      .find(|work| identifier.matches(&work.entry))
+  }
+
   pub fn delete_entry(&mut self, identifier: &EntryIdentifier) -> Result<(), Box<dyn Error>> {
+    let deleted: Vec<Event> = self
+      .config
+      .entries
+      .iter()
+      .filter(|entry| identifier.matches(entry))
+      .map(|entry| Event::new(EventKind::EntryDeleted, Some(entry)))
+      .collect();
     self.config.delete_entry(identifier);
     self.write_conf()?;
+    self.works.retain(|work| !identifier.matches(&work.entry));
+    deleted.into_iter().for_each(events::publish);
     Ok(())
   }
 
   pub fn edit_entry(&mut self, entry: &Entry) -> Result<(), Box<dyn Error>> {
-    self.config.edit_entry(entry)
+    self.config.edit_entry(entry)?;
+    self.write_conf()?;
+    self.works.retain(|work| work.entry.id != entry.id);
+    if entry.enabled {
+      self.works.push(Work::new(entry.clone()));
+    }
+    events::publish(Event::new(EventKind::EntryEdited, Some(entry)));
+    Ok(())
   }
 
-  pub fn stop_daemon(&mut self) {
-    self.daemon_status = RtodoDaemonStatus::Stopped;
+  /// Re-reads the config file. Works of unchanged entries keep their state,
+  /// edited entries get a fresh schedule but keep their processes and queue.
+  pub fn reload_conf(&mut self) -> Result<(), Box<dyn Error>> {
+    let config: Config = serde_json::from_slice(&fs::read(&self.conf_path)?)?;
+    let mut works = Vec::new();
+    for entry in config.entries.iter().filter(|entry| entry.enabled) {
+      let old = self
+        .works
+        .iter()
+        .position(|work| work.entry.id == entry.id)
+        .map(|index| self.works.remove(index));
+      let work = match old {
+        Some(mut work) => {
+          if serde_json::to_value(&work.entry)? != serde_json::to_value(entry)? {
+            let mut new = Work::new(entry.clone());
+            new.running_processes = std::mem::take(&mut work.running_processes);
+            new.queued_runs = std::mem::take(&mut work.queued_runs);
+            new.finished_runs = std::mem::take(&mut work.finished_runs);
+            if !new.running_processes.is_empty() && new.status == Status::Pending {
+              new.status = Status::Running;
+            }
+            work = new;
+          }
+          work
+        }
+        None => Work::new(entry.clone()),
+      };
+      works.push(work);
+    }
+    self.cur_entry_id = self.cur_entry_id.max(
+      config
+        .entries
+        .iter()
+        .map(|entry| entry.id)
+        .max()
+        .unwrap_or(0),
+    );
+    self.config = config;
+    self.works = works;
+    info!("Info: Reloaded config file: {}", self.conf_path);
+    events::publish(Event::new(EventKind::ConfigReloaded, None));
+    Ok(())
   }
-}
 
-impl<T> ResCommonData<T> {
-  pub fn new(code: i32, data: T) -> Self {
-    Self { code, data }
+  /// Counts running processes, queued runs and lock usage over all works.
+  pub fn job_counts(&self) -> JobCounts {
+    let mut counts = JobCounts::default();
+    for work in self.works.iter() {
+      counts.add(work, work.running_processes.len());
+      counts.queued += work.queued_runs.len();
+    }
+    counts
   }
-}
 
-impl<T> ReqCommonData<T> {
-  pub fn check_token(&self, rtodo: &Rtodo) -> bool {
-    self.token == rtodo.get_token()
+  /// Works of the entries the grant covers.
+  pub fn get_works(&self, grant: &Grant) -> Vec<&Work> {
+    self
+      .works
+      .iter()
+      .filter(|work| grant.covers_entry(&work.entry))
+      .collect()
   }
-}
 
-impl DateTime {
-  pub fn from_ymd_hms(
-    year: i32,
-    month: u32,
-    day: u32,
-    hour: u32,
-    min: u32,
-    sec: u32,
-  ) -> Option<Self> {
-    let new = match chrono::Local.with_ymd_and_hms(year, month, day, hour, min, sec) {
-      chrono::LocalResult::Single(data) => data,
-      chrono::LocalResult::Ambiguous(dt, dt2) => {
+  pub fn get_locks(&self) -> Vec<LockState> {
+    let mut locks: Vec<LockState> = Vec::new();
+    for work in self.works.iter() {
+      for name in work.entry.locks.iter() {
+        let index = match locks.iter().position(|lock| lock.name == *name) {
+          Some(index) => index,
+          None => {
+            locks.push(LockState {
+              name: name.clone(),
+              capacity: self.config.lock_capacity(name),
+              holders: Vec::new(),
+            });
+            locks.len() - 1
+          }
+        };
+        if !work.running_processes.is_empty() {
+          locks[index].holders.push(LockHolder {
+            entry_id: work.entry.id,
+            entry_name: work.entry.name.clone(),
+            pids: work.running_processes.iter().map(|p| p.pid).collect(),
+          });
+        }
+      }
+    }
+    locks
+  }
+
+  /// Running and finished runs of all works, oldest first.
+  pub fn get_runs(&self) -> Vec<RunInfo> {
+    let mut runs: Vec<RunInfo> = Vec::new();
+    for work in self.works.iter() {
+      for process in work.running_processes.iter() {
+        runs.push(RunInfo::new(&work.entry, process));
+      }
+      for run in work.finished_runs.iter() {
+        runs.push(RunInfo::finished(&work.entry, run));
+      }
+    }
+    runs.sort_by_key(|run| run.id);
+    runs
+  }
+
+  /// Like `get_runs` for a single run, without collecting the others.
+  pub fn find_run(&self, id: u64) -> Option<RunInfo> {
+    self.works.iter().find_map(|work| {
+      match work
+        .running_processes
+        .iter()
+        .find(|process| process.run_id == id)
+      {
+        Some(process) => Some(RunInfo::new(&work.entry, process)),
+        None => work
+          .finished_runs
+          .iter()
+          .find(|run| run.process.run_id == id)
+          .map(|run| RunInfo::finished(&work.entry, run)),
+      }
+    })
+  }
+
+  pub fn run_entry(&mut self, run: &RunEntry) -> Result<RunOutcome, Box<dyn Error>> {
+    if self.is_stopping() {
+      return Err("The daemon is stopping".into());
+    }
+    let index = self
+      .works
+      .iter()
+      .position(|work| run.entry.matches(&work.entry))
+      .ok_or(format!("Entry {} not found or disabled", run.entry))?;
+    // Settled now, a queued run starts as asked for.
+    if !self.works[index].make_room()? {
+      return Ok(RunOutcome::Stopped);
+    }
+    let counts = self.job_counts();
+    let work = &mut self.works[index];
+    match self.config.check_limits(work, &counts) {
+      Ok(_) => {
+        work.run(run, &DateTime::now())?;
+        Ok(RunOutcome::Started)
+      }
+      Err(reason) => {
+        self
+          .config
+          .queue_run(&self.queue_seq, work, run.clone(), &counts, &reason)?;
+        Ok(RunOutcome::Queued { reason })
+      }
+    }
+  }
+
+  /// Starts a scheduled run of the work at `index` if the limits allow it,
+  /// otherwise consumes the occurrence and queues or drops it per the entry's
+  /// overflow policy.
+  pub fn start_or_queue(
+    &mut self,
+    index: usize,
+    counts: &mut JobCounts,
+  ) -> Result<(), Box<dyn Error>> {
+    let work = &mut self.works[index];
+    let reason = match self.config.check_limits(work, counts) {
+      Ok(_) => {
+        let before = work.running_processes.len();
+        work.start()?;
+        counts.add(work, work.running_processes.len().saturating_sub(before));
+        return Ok(());
+      }
+      Err(reason) => reason,
+    };
+    work.advance_trigger()?;
+    let run = RunEntry {
+      entry: EntryIdentifier::Id(work.entry.id),
+      args: None,
+      env: None,
+    };
+    match self
+      .config
+      .queue_run(&self.queue_seq, work, run, counts, &reason)
+    {
+      Ok(_) => counts.queued += 1,
+      Err(err) => error!("Error: {}", err),
+    }
+    Ok(())
+  }
+
+  /// Starts queued runs in FIFO order across all works while limits allow.
+  pub fn start_queued_runs(&mut self, counts: &mut JobCounts) {
+    let mut seqs: Vec<(u64, usize)> = self
+      .works
+      .iter()
+      .enumerate()
+      .flat_map(|(index, work)| {
+        work
+          .queued_runs
+          .iter()
+          .map(move |queued_run| (queued_run.seq, index))
+      })
+      .collect();
+    seqs.sort();
+    for (seq, index) in seqs {
+      let work = &mut self.works[index];
+      match work.queued_runs.front() {
+        Some(queued_run) if queued_run.seq == seq => (),
+        _ => continue,
+      }
+      if !work.can_start_queued() || self.config.check_limits(work, counts).is_err() {
+        continue;
+      }
+      let queued_run = work.queued_runs.pop_front().unwrap();
+      counts.queued -= 1;
+      let before = work.running_processes.len();
+      match work.run(&queued_run.run, &queued_run.queued_at) {
+        Ok(_) => counts.add(work, work.running_processes.len().saturating_sub(before)),
+        Err(err) => error!(
+          "Error: Failed in start queued run of entry {}, Error Info: {}",
+          work.entry.name, err
+        ),
+      }
+    }
+  }
+
+  fn update_entry<F: Fn(&mut Entry)>(
+    &mut self,
+    identifier: &EntryIdentifier,
+    update: F,
+  ) -> Result<(), Box<dyn Error>> {
+    let mut found = false;
+    for entry in self.config.entries.iter_mut() {
+      if identifier.matches(entry) {
+        update(entry);
+        found = true;
+      }
+    }
+    if !found {
+      return Err(format!("Entry {} not found", identifier).into());
+    }
+    self.write_conf()?;
+    for work in self.works.iter_mut() {
+      if identifier.matches(&work.entry) {
+        update(&mut work.entry);
+      }
+    }
+    Ok(())
+  }
+
+  pub fn pause_entry(&mut self, pause: &PauseEntry) -> Result<(), Box<dyn Error>> {
+    self.update_entry(&pause.entry, |entry| {
+      entry.status = Status::Paused;
+      entry.paused_until = pause.until.clone();
+    })?;
+    if let Some(work) = self.find_work(&pause.entry) {
+      work.set_status(Status::Paused);
+    }
+    Ok(())
+  }
+
+  pub fn resume_entry(&mut self, identifier: &EntryIdentifier) -> Result<(), Box<dyn Error>> {
+    self.update_entry(identifier, |entry| {
+      entry.status = Status::Pending;
+      entry.paused_until = None;
+    })?;
+    if let Some(work) = self.find_work(identifier) {
+      work.resume();
+    }
+    Ok(())
+  }
+
+  pub fn skip_entry(&mut self, identifier: &EntryIdentifier) -> Result<(), Box<dyn Error>> {
+    self
+      .find_work(identifier)
+      .ok_or(format!("Entry {} not found or disabled", identifier))?
+      .skip_next()
+  }
+
+  pub fn call_api<T: Serialize, R: DeserializeOwned>(
+    &self,
+    path: &str,
+    data: T,
+  ) -> Result<ResCommonData<R>, Box<dyn Error>> {
+    let res = self
+      .rcli
+      .post(format!("{}/{}", self.api_url, path))
+      .json(&ReqCommonData {
+        token: self.config.token.clone(),
+        data: Some(data),
+      })
+      .send()?;
+    if !res.status().is_success() {
+      return Err(format!("Daemon returned status {}", res.status()).into());
+    }
+    Ok(res.json()?)
+  }
+
+  pub fn get_status(&self) -> Result<DaemonInfo, Box<dyn Error>> {
+    let res = self
+      .rcli
+      .get(format!("{}/v1/status", self.api_url))
+      .bearer_auth(&self.config.token)
+      .send()?;
+    let status = res.status();
+    if !status.is_success() {
+      return Err(format!("Daemon returned status {}, {}", status, res.text()?).into());
+    }
+    Ok(res.json()?)
+  }
+
+  /// GETs a route of the daemon whose response may be a long running stream.
+  fn get_stream<Q: Serialize + ?Sized>(
+    &self,
+    path: &str,
+    query: &Q,
+  ) -> Result<reqwest::blocking::Response, Box<dyn Error>> {
+    let (client, api_url) = daemon_client(&self.config)?;
+    let res = client
+      // The default timeout would cut streams after 30 seconds.
+      .timeout(None)
+      .build()?
+      .get(format!("{}/{}", api_url, path))
+      .bearer_auth(&self.config.token)
+      .query(query)
+      .send()?;
+    let status = res.status();
+    if !status.is_success() {
+      return Err(format!("Daemon returned status {}, {}", status, res.text()?).into());
+    }
+    Ok(res)
+  }
+
+  /// Prints the daemon's events as they arrive, until the stream is closed.
+  pub fn watch_events(&self, entry: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let query: Vec<(&str, &str)> = entry.map(|entry| ("entry", entry)).into_iter().collect();
+    let res = self.get_stream("events", &query)?;
+    for line in BufReader::new(res).lines() {
+      if let Some(data) = line?.strip_prefix("data: ") {
+        println!("{}", serde_json::from_str::<Event>(data)?);
+      }
+    }
+    Ok(())
+  }
+
+  /// Copies the output of a run of the entry, its latest by default, to stdout.
+  pub fn print_logs(&self, logs: &LogsEntry) -> Result<(), Box<dyn Error>> {
+    let run = match logs.run {
+      Some(run) => run,
+      None => self
+        .get_stream("runs", &[("entry", logs.entry.to_string())])?
+        .json::<Vec<RunInfo>>()?
+        .last()
+        .map(|run| run.id)
+        .ok_or(format!("Entry {} has no runs", logs.entry))?,
+    };
+    let mut res = self.get_stream(
+      &format!("runs/{}/log", run),
+      &[("follow", logs.follow.to_string())],
+    )?;
+    io::copy(&mut res, &mut io::stdout())?;
+    Ok(())
+  }
+
+  /// Starts no new runs from here on, the core exits once running jobs are
+  /// dealt with per `config.shutdown`.
+  pub fn stop_daemon(&mut self) {
+    if let RtodoDaemonStatus::Running = self.daemon_status {
+      info!("Info: stopping daemon");
+      self.daemon_status = RtodoDaemonStatus::Stopped;
+    }
+  }
+
+  pub fn is_stopping(&self) -> bool {
+    matches!(self.daemon_status, RtodoDaemonStatus::Stopped)
+  }
+}
+
+impl ApiError {
+  pub fn new(kind: ApiErrorKind, message: impl Into<String>) -> Self {
+    Self {
+      kind,
+      message: message.into(),
+    }
+  }
+
+  pub fn unauthorized() -> Self {
+    Self::new(ApiErrorKind::Unauthorized, "Invalid token")
+  }
+
+  pub fn forbidden(message: String) -> Self {
+    Self::new(ApiErrorKind::Forbidden, message)
+  }
+
+  pub fn entry_not_found(identifier: &EntryIdentifier) -> Self {
+    Self::new(
+      ApiErrorKind::NotFound,
+      format!("Entry {} not found", identifier),
+    )
+  }
+
+  pub fn internal(err: Box<dyn Error>) -> Self {
+    Self::new(ApiErrorKind::Internal, err.to_string())
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?}: {}", self.kind, self.message)
+  }
+}
+
+impl ResponseError for ApiError {
+  fn status_code(&self) -> StatusCode {
+    match self.kind {
+      ApiErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+      ApiErrorKind::Forbidden => StatusCode::FORBIDDEN,
+      ApiErrorKind::NotFound => StatusCode::NOT_FOUND,
+      ApiErrorKind::Conflict => StatusCode::CONFLICT,
+      ApiErrorKind::Invalid => StatusCode::UNPROCESSABLE_ENTITY,
+      ApiErrorKind::OutOfRange => StatusCode::RANGE_NOT_SATISFIABLE,
+      ApiErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    let mut res = HttpResponse::build(self.status_code());
+    if let ApiErrorKind::Unauthorized = self.kind {
+      res.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+    }
+    res.json(self)
+  }
+}
+
+impl Event {
+  pub fn new(kind: EventKind, entry: Option<&Entry>) -> Self {
+    Self {
+      time: DateTime::now(),
+      entry_id: entry.map(|entry| entry.id),
+      entry_name: entry.map(|entry| entry.name.clone()),
+      entry_tags: entry.map(|entry| entry.tags.clone()).unwrap_or_default(),
+      kind,
+    }
+  }
+}
+
+impl fmt::Display for Event {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.time)?;
+    if let (Some(id), Some(name)) = (self.entry_id, &self.entry_name) {
+      write!(f, " [{}:{}]", id, name)?;
+    }
+    write!(f, " {}", self.kind)
+  }
+}
+
+impl fmt::Display for EventKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::RunStarted { run_id, pid } => write!(f, "run {} started, pid {}", run_id, pid),
+      Self::RunFinished { run_id, pid } => write!(f, "run {} finished, pid {}", run_id, pid),
+      Self::RunFailed {
+        run_id: Some(run_id),
+        pid: Some(pid),
+        reason,
+      } => write!(f, "run {} failed, pid {}, {}", run_id, pid, reason),
+      Self::RunFailed { reason, .. } => write!(f, "run failed to start, {}", reason),
+      Self::StatusChanged { from, to } => write!(f, "status {:?} -> {:?}", from, to),
+      Self::EntryAdded => write!(f, "entry added"),
+      Self::EntryEdited => write!(f, "entry edited"),
+      Self::EntryDeleted => write!(f, "entry deleted"),
+      Self::ConfigReloaded => write!(f, "config reloaded"),
+    }
+  }
+}
+
+impl EntryFilter {
+  /// Events without an entry, e.g. config reloads, always pass.
+  pub fn matches(&self, event: &Event) -> bool {
+    match (event.entry_id, &event.entry_name) {
+      (Some(id), Some(name)) => self.matches_entry(id, name),
+      _ => true,
+    }
+  }
+
+  pub fn matches_entry(&self, id: u32, name: &str) -> bool {
+    let entry = match &self.entry {
+      Some(entry) => entry,
+      None => return true,
+    };
+    entry
+      .split(',')
+      .map(|identifier| EntryIdentifier::parse(identifier.trim()))
+      .any(|identifier| match identifier {
+        EntryIdentifier::Id(i) => i == id,
+        EntryIdentifier::Name(n) => n == name,
+      })
+  }
+}
+
+impl RunInfo {
+  pub fn new(entry: &Entry, process: &Process) -> Self {
+    Self {
+      id: process.run_id,
+      entry_id: entry.id,
+      entry_name: entry.name.clone(),
+      pid: process.pid,
+      started_at: process.started_at.clone(),
+      finished_at: None,
+      error: None,
+      limits_hit: Vec::new(),
+      output_file: process.output_tmp_file.clone(),
+      output_start: process.output_start,
+      output_end: None,
+    }
+  }
+
+  pub fn finished(entry: &Entry, run: &FinishedRun) -> Self {
+    Self {
+      finished_at: Some(run.finished_at.clone()),
+      error: run.error.clone(),
+      limits_hit: run.limits_hit.clone(),
+      output_end: run.output_end,
+      ..Self::new(entry, &run.process)
+    }
+  }
+}
+
+impl<T> ResCommonData<T> {
+  pub fn new(code: i32, data: T) -> Self {
+    Self { code, data }
+  }
+}
+
+impl RtodoState {
+  /// Runs `read` on the core and waits for its result.
+  pub async fn read<T: Send + 'static>(
+    &self,
+    read: impl FnOnce(&Rtodo) -> T + Send + 'static,
+  ) -> Result<T, ApiError> {
+    let (sender, receiver) = oneshot::channel();
+    self
+      .send(
+        Command::Read(Box::new(move |rtodo| {
+          let _ = sender.send(read(rtodo));
+        })),
+        receiver,
+      )
+      .await
+  }
+
+  /// Like `read`, for operations that change rtodo.
+  pub async fn write<T: Send + 'static>(
+    &self,
+    write: impl FnOnce(&mut Rtodo) -> T + Send + 'static,
+  ) -> Result<T, ApiError> {
+    let (sender, receiver) = oneshot::channel();
+    self
+      .send(
+        Command::Write(Box::new(move |rtodo| {
+          let _ = sender.send(write(rtodo));
+        })),
+        receiver,
+      )
+      .await
+  }
+
+  async fn send<T>(&self, command: Command, receiver: oneshot::Receiver<T>) -> Result<T, ApiError> {
+    let stopped = || ApiError::new(ApiErrorKind::Internal, "The daemon's core has stopped");
+    self.core.send(command).map_err(|_| stopped())?;
+    receiver.await.map_err(|_| stopped())
+  }
+}
+
+impl Origin {
+  pub fn new(req: &HttpRequest) -> Self {
+    let client = match req.conn_data::<SocketPeer>() {
+      Some(SocketPeer(Some(cred))) => format!("unix uid {}", cred.uid()),
+      Some(SocketPeer(None)) => String::from("unix"),
+      None => req
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default(),
+    };
+    Self {
+      client,
+      operation: format!("{} {}", req.method(), req.path()),
+    }
+  }
+}
+
+impl AuditRecord {
+  pub fn new(origin: &Origin, grant: &Grant) -> Self {
+    Self {
+      time: DateTime::now(),
+      client: origin.client.clone(),
+      token: grant.name.clone(),
+      operation: origin.operation.clone(),
+      entry: None,
+      before: None,
+      after: None,
+      error: None,
+    }
+  }
+
+  pub fn with_entry(
+    mut self,
+    identifier: &EntryIdentifier,
+    before: Option<&Entry>,
+    after: Option<&Entry>,
+  ) -> Self {
+    self.entry = Some(match after.or(before) {
+      Some(entry) => entry.name.clone(),
+      None => identifier.to_string(),
+    });
+    self.with_change(
+      before.and_then(|entry| serde_json::to_value(entry).ok()),
+      after.and_then(|entry| serde_json::to_value(entry).ok()),
+    )
+  }
+
+  /// Of objects on both sides only the fields that differ are kept.
+  pub fn with_change(
+    mut self,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+  ) -> Self {
+    (self.before, self.after) = match (before, after) {
+      (Some(serde_json::Value::Object(mut before)), Some(serde_json::Value::Object(mut after))) => {
+        let unchanged: Vec<String> = before
+          .iter()
+          .filter(|(key, value)| after.get(*key) == Some(*value))
+          .map(|(key, _)| key.clone())
+          .collect();
+        for key in unchanged {
+          before.remove(&key);
+          after.remove(&key);
+        }
+        if before.is_empty() && after.is_empty() {
+          (None, None)
+        } else {
+          (
+            Some(serde_json::Value::Object(before)),
+            Some(serde_json::Value::Object(after)),
+          )
+        }
+      }
+      change => change,
+    };
+    self
+  }
+
+  pub fn with_result<T, E: fmt::Display>(mut self, res: &Result<T, E>) -> Self {
+    self.error = res.as_ref().err().map(|err| err.to_string());
+    self
+  }
+
+  pub fn with_error(mut self, err: impl fmt::Display) -> Self {
+    self.error = Some(err.to_string());
+    self
+  }
+}
+
+/// Name of the grant of the shared `token` in the config.
+const DEFAULT_TOKEN_NAME: &str = "default";
+
+impl ApiToken {
+  /// Returns the token along with the secret to hand out, which isn't kept.
+  pub fn generate(new: NewToken) -> (Self, String) {
+    let token = format!("rtodo_{}{}", generate_token(), generate_token());
+    let salt = generate_token();
+    let api_token = Self {
+      name: new.name,
+      scope: new.scope,
+      entries: new.entries,
+      tags: new.tags,
+      created_at: DateTime::now(),
+      hash: hash_token(&salt, &token),
+      salt,
+    };
+    (api_token, token)
+  }
+
+  pub fn verify(&self, token: &str) -> bool {
+    constant_time_eq(&hash_token(&self.salt, token), &self.hash)
+  }
+}
+
+impl From<&ApiToken> for Grant {
+  fn from(token: &ApiToken) -> Self {
+    Self {
+      name: token.name.clone(),
+      scope: token.scope,
+      entries: token.entries.clone(),
+      tags: token.tags.clone(),
+    }
+  }
+}
+
+impl From<&ApiToken> for TokenInfo {
+  fn from(token: &ApiToken) -> Self {
+    Self {
+      name: token.name.clone(),
+      scope: token.scope,
+      entries: token.entries.clone(),
+      tags: token.tags.clone(),
+      created_at: token.created_at.clone(),
+    }
+  }
+}
+
+impl fmt::Display for DaemonInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "running, pid {}, version {}, up ",
+      self.pid, self.version
+    )?;
+    if self.uptime >= 86400 {
+      write!(f, "{}d ", self.uptime / 86400)?;
+    }
+    write!(
+      f,
+      "{:02}:{:02}:{:02}",
+      self.uptime % 86400 / 3600,
+      self.uptime % 3600 / 60,
+      self.uptime % 60
+    )?;
+    if self.stopping {
+      write!(f, ", stopping")?;
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for TokenInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} {:?}, created {}",
+      self.name, self.scope, self.created_at
+    )?;
+    if !self.entries.is_empty() {
+      write!(f, ", entries {}", self.entries.join(","))?;
+    }
+    if !self.tags.is_empty() {
+      write!(f, ", tags {}", self.tags.join(","))?;
+    }
+    Ok(())
+  }
+}
+
+impl std::str::FromStr for TokenScope {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "read" => Ok(Self::Read),
+      "operate" => Ok(Self::Operate),
+      "admin" => Ok(Self::Admin),
+      _ => Err(format!("Invalid scope {}", s)),
+    }
+  }
+}
+
+impl Grant {
+  pub fn require(&self, scope: TokenScope) -> Result<(), String> {
+    if self.scope >= scope {
+      Ok(())
+    } else {
+      Err(format!("Token {} lacks the {:?} scope", self.name, scope))
+    }
+  }
+
+  /// For operations that aren't about a single entry, e.g. stopping the daemon.
+  pub fn require_all(&self, scope: TokenScope) -> Result<(), String> {
+    self.require(scope)?;
+    if self.is_restricted() {
+      return Err(format!("Token {} is limited to some entries", self.name));
+    }
+    Ok(())
+  }
+
+  pub fn require_entry(&self, scope: TokenScope, entry: &Entry) -> Result<(), String> {
+    self.require(scope)?;
+    if !self.covers_entry(entry) {
+      return Err(format!(
+        "Token {} is not allowed on entry {}",
+        self.name, entry.name
+      ));
+    }
+    Ok(())
+  }
+
+  pub fn is_restricted(&self) -> bool {
+    !self.entries.is_empty() || !self.tags.is_empty()
+  }
+
+  fn covers(&self, id: u32, name: &str, tags: &[String]) -> bool {
+    !self.is_restricted()
+      || self
+        .entries
+        .iter()
+        .any(|identifier| match EntryIdentifier::parse(identifier) {
+          EntryIdentifier::Id(i) => i == id,
+          EntryIdentifier::Name(n) => n == name,
+        })
+      || self.tags.iter().any(|tag| tags.contains(tag))
+  }
+
+  pub fn covers_entry(&self, entry: &Entry) -> bool {
+    self.covers(entry.id, &entry.name, &entry.tags)
+  }
+
+  /// Events without an entry, e.g. config reloads, reach every token.
+  pub fn covers_event(&self, event: &Event) -> bool {
+    match (event.entry_id, &event.entry_name) {
+      (Some(id), Some(name)) => self.covers(id, name, &event.entry_tags),
+      _ => true,
+    }
+  }
+}
+
+impl DateTime {
+  pub fn from_ymd_hms(
+    year: i32,
+    month: u32,
+    day: u32,
+    hour: u32,
+    min: u32,
+    sec: u32,
+  ) -> Option<Self> {
+    let new = match chrono::Local.with_ymd_and_hms(year, month, day, hour, min, sec) {
+      chrono::LocalResult::Single(data) => data,
+      chrono::LocalResult::Ambiguous(dt, dt2) => {
         #[cfg(debug_assertions)]
         println!("Ambiguous time: {} or {}", dt, dt2);
         dt
@@ -397,6 +1822,18 @@ impl DateTime {
     })
   }
 
+  pub fn parse(s: &str) -> Option<Self> {
+    let new = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").ok()?;
+    Self::from_ymd_hms(
+      new.year(),
+      new.month(),
+      new.day(),
+      new.hour(),
+      new.minute(),
+      new.second(),
+    )
+  }
+
   pub fn from_args(args: &[String]) -> Option<Self> {
     let mut hasarg = false;
     let mut datetime = Self::default();
@@ -460,7 +1897,15 @@ impl DateTime {
       }
     }
     if hasarg {
-      Some(datetime)
+      // Fills in the timestamp, which is what the schedule goes by.
+      Self::from_ymd_hms(
+        datetime.year,
+        datetime.month,
+        datetime.day,
+        datetime.hour,
+        datetime.min,
+        datetime.sec,
+      )
     } else {
       None
     }
@@ -470,6 +1915,14 @@ impl DateTime {
     Self::now() + duration.clone()
   }
 
+  pub fn to_rfc3339(&self) -> String {
+    chrono::Local
+      .timestamp_opt(self.timestamp, 0)
+      .single()
+      .map(|time| time.to_rfc3339())
+      .unwrap_or_default()
+  }
+
   pub fn now() -> Self {
     let now = chrono::Local::now();
     Self {
@@ -497,23 +1950,18 @@ impl DateTime {
     }
   }
 
-  pub fn timestamp(&self) -> i64 {
-    chrono::Local
-      .with_ymd_and_hms(
-        self.year, self.month, self.day, self.hour, self.min, self.sec,
-      )
-      .unwrap()
-      .timestamp()
+  pub fn is_up(&self) -> bool {
+    self.timestamp <= chrono::Local::now().timestamp()
   }
+}
 
-  pub fn is_up(&self) -> bool {
-    /*#[cfg(debug_assertions)]
-    println!(
-      "self.timestamp(): {}, chrono::Local::now().timestamp(): {}",
-      self.timestamp(),
-      chrono::Local::now().timestamp()
-    );*/
-    self.timestamp() <= chrono::Local::now().timestamp()
+impl fmt::Display for DateTime {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+      self.year, self.month, self.day, self.hour, self.min, self.sec
+    )
   }
 }
 
@@ -629,6 +2077,14 @@ impl Duration {
       None
     }
   }
+  pub fn from_sec(sec: u32) -> Self {
+    Self {
+      sec,
+      total_sec: sec as u64,
+      ..Default::default()
+    }
+  }
+
   pub fn one_day() -> Self {
     Self {
       sec: 0,
@@ -666,98 +2122,464 @@ impl Timer {
         _ => (),
       }
     }
-    if hasarg {
-      Some(timer)
-    } else {
-      None
+    if hasarg {
+      Some(timer)
+    } else {
+      None
+    }
+  }
+}
+
+/// Expands the value of `name` in `env`, whose variables may use each other.
+/// One used while being expanded, like `PATH` in `PATH=$PATH:/opt/bin`,
+/// stands for its value in `vars`.
+fn resolve_var(
+  name: &str,
+  env: &HashMap<String, String>,
+  vars: &HashMap<String, String>,
+  resolved: &mut HashMap<String, String>,
+  expanding: &mut Vec<String>,
+) -> String {
+  if let Some(value) = resolved.get(name) {
+    return value.clone();
+  }
+  expanding.push(name.to_string());
+  let value = expand_vars(&env[name], &mut |var| match env.contains_key(var)
+    && !expanding.iter().any(|name| name == var)
+  {
+    true => Some(resolve_var(var, env, vars, resolved, expanding)),
+    false => vars.get(var).cloned(),
+  });
+  expanding.pop();
+  resolved.insert(name.to_string(), value.clone());
+  value
+}
+
+impl Execute {
+  /// Reads the options of an exec, its executable is empty without `--exec`.
+  pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+    let mut execute = Self::default();
+    for (index, arg) in args.iter().enumerate() {
+      match arg.as_str() {
+        "--exec" => {
+          execute.executable = PathBuf::from(match garg::<String>(args, index + 1) {
+            Some(data) => data,
+            None => continue,
+          });
+        }
+        "--env" => {
+          execute.env = garg::<String>(args, index + 1)
+            .map(|data| env_pairs(&data))
+            .transpose()?
+        }
+        "--env-file" => execute.env_files.extend(garg::<String>(args, index + 1)),
+        "--inherit-env" => {
+          execute.inherit_env = match garg::<String>(args, index + 1).as_deref() {
+            Some("all") => InheritEnv::All,
+            Some("clean") => InheritEnv::Clean,
+            Some(names) => {
+              InheritEnv::Only(names.split(',').map(|name| name.to_string()).collect())
+            }
+            None => continue,
+          }
+        }
+        "--args" => {
+          execute.args = garg::<String>(args, index + 1)
+            .map(|data| split_args(&data))
+            .transpose()?
+        }
+        "--stdin" => execute.stdin = garg(args, index + 1),
+        "--dir" => execute.working_dir = garg(args, index + 1),
+        "--username" => execute.user = SystemUser::from_un(garg(args, index + 1)),
+        "--sandbox" => {
+          execute.sandbox = garg::<String>(args, index + 1)
+            .map(|data| Sandbox::from_arg(&data).map(Box::new))
+            .transpose()?
+        }
+        "--limits" => {
+          execute.limits = garg::<String>(args, index + 1)
+            .map(|data| Limits::from_arg(&data).map(Box::new))
+            .transpose()?
+        }
+        _ => (),
+      }
+    }
+    Ok(execute)
+  }
+
+  /// The environment of a run: the inherited variables, `env_files`, then
+  /// `env`. The variables of the run in `run_vars` come first, so that the
+  /// others may use them, and again last, so that they hold.
+  pub fn environment(
+    &self,
+    run_vars: &[(&str, String)],
+  ) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut vars: HashMap<String, String> = match &self.inherit_env {
+      InheritEnv::All => env::vars().collect(),
+      InheritEnv::Clean => HashMap::new(),
+      InheritEnv::Only(names) => env::vars()
+        .filter(|(name, _)| names.contains(name))
+        .collect(),
+    };
+    let run_vars = run_vars
+      .iter()
+      .map(|(name, value)| (name.to_string(), value.clone()));
+    vars.extend(run_vars.clone());
+    for file in &self.env_files {
+      let content = fs::read_to_string(file)
+        .map_err(|err| format!("Cannot read env file {}, Err: {}", file, err))?;
+      parse_dotenv(&content, &mut vars).map_err(|err| format!("{} of env file {}", err, file))?;
+    }
+    if let Some(env) = &self.env {
+      let mut resolved = HashMap::new();
+      for name in env.keys() {
+        resolve_var(name, env, &vars, &mut resolved, &mut Vec::new());
+      }
+      vars.extend(resolved);
+    }
+    vars.extend(run_vars);
+    Ok(vars)
+  }
+
+  pub fn with_overrides(&self, run: &RunEntry) -> Self {
+    let mut execute = self.clone();
+    if let Some(args) = &run.args {
+      execute.args = Some(args.clone());
+    }
+    if let Some(env) = &run.env {
+      execute
+        .env
+        .get_or_insert_with(HashMap::new)
+        .extend(env.clone());
+    }
+    execute
+  }
+
+  /// Spawns the executable in `env`, see `environment`, with stdout and
+  /// stderr appended to `output`, discarding them if there is none. It leads
+  /// a session of its own, in `cgroup` if there is one, so that all it starts
+  /// can be told apart.
+  pub fn exec(
+    &self,
+    output: Option<&Path>,
+    cgroup: Option<&Path>,
+    env: &HashMap<String, String>,
+  ) -> Result<u32, Box<dyn Error>> {
+    let working_dir = self.working_dir.clone().unwrap_or("/tmp".into());
+    let mut command = process::Command::new(&self.executable);
+    command
+      .args(self.args.clone().unwrap_or(vec![]))
+      .env_clear()
+      .envs(env)
+      .current_dir(&working_dir);
+    let procs = cgroup.map(cgroup::procs_file).transpose()?;
+    let limits = self.limits.clone();
+    let sandbox = self
+      .sandbox
+      .as_ref()
+      .map(|sandbox| sandbox::prepare(sandbox, &working_dir))
+      .transpose()?;
+    unsafe {
+      command.pre_exec(move || {
+        setsid()?;
+        if let Some(procs) = &procs {
+          cgroup::enter(procs)?;
+        }
+        if let Some(limits) = &limits {
+          limits.apply()?;
+        }
+        if let Some(sandbox) = &sandbox {
+          sandbox.enter()?;
+        }
+        Ok(())
+      });
+    }
+    match output {
+      Some(path) => {
+        let file = match path.starts_with(output_dir()) {
+          true => create_output_file(path)?,
+          false => {
+            let mut options = fs::OpenOptions::new();
+            options.create(true).append(true);
+            // Output may well contain secrets.
+            #[cfg(target_family = "unix")]
+            options.mode(0o600);
+            options.open(path)?
+          }
+        };
+        command.stdout(file.try_clone()?).stderr(file);
+      }
+      None => {
+        command
+          .stdout(process::Stdio::null())
+          .stderr(process::Stdio::null());
+      }
+    }
+    if self.stdin.is_some() {
+      command.stdin(process::Stdio::piped());
+    }
+    let mut child = command.spawn()?;
+    let pid = child.id();
+    if let (Some(content), Some(mut stdin)) = (self.stdin.clone(), child.stdin.take()) {
+      // Off the core, the job may take its time reading.
+      thread::spawn(move || {
+        if let Err(err) = stdin.write_all(content.as_bytes()) {
+          if err.kind() != io::ErrorKind::BrokenPipe {
+            error!(
+              "Error: Failed to write stdin of pid {}, Err: {}",
+              child.id(),
+              err
+            );
+          }
+        }
+      });
+    }
+    Ok(pid)
+  }
+}
+
+impl Limits {
+  /// `ioprio_set(2)` argument for the calling process.
+  const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+
+  /// Reads pairs like `memory=512M cpus=0.5 ionice=best-effort:4`, sizes are
+  /// bytes unless suffixed, see `parse_size`.
+  pub fn from_arg(arg: &str) -> Result<Self, Box<dyn Error>> {
+    fn number<T: FromStr>(key: &str, value: &str) -> Result<Option<T>, String> {
+      value
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("Invalid {} limit {}", key, value))
+    }
+    let mut limits = Self::default();
+    for pair in arg.split_whitespace() {
+      let (key, value) = pair
+        .split_once('=')
+        .ok_or_else(|| format!("Expected key=value, got {}", pair))?;
+      match key {
+        "cpu_time" => limits.cpu_time = number(key, value)?,
+        "address_space" => limits.address_space = Some(parse_size(value)?),
+        "open_files" => limits.open_files = number(key, value)?,
+        "processes" => limits.processes = number(key, value)?,
+        "memory" => limits.memory = Some(parse_size(value)?),
+        "cpus" => {
+          limits.cpus = match value.parse::<f64>() {
+            Ok(cpus) if cpus.is_finite() && cpus > 0.0 => Some(cpus),
+            _ => return Err(format!("Invalid cpus limit {}", value).into()),
+          }
+        }
+        "pids" => limits.pids = number(key, value)?,
+        "nice" => limits.nice = number(key, value)?,
+        "ionice" => {
+          limits.ionice = Some(match value.split_once(':') {
+            Some(("realtime", level)) => IoNice::Realtime(Self::io_level(level)?),
+            Some(("best-effort", level)) => IoNice::BestEffort(Self::io_level(level)?),
+            None if value == "idle" => IoNice::Idle,
+            _ => {
+              return Err(
+                format!(
+                  "Invalid ionice {}, expected realtime:N, best-effort:N or idle",
+                  value
+                )
+                .into(),
+              )
+            }
+          })
+        }
+        _ => return Err(format!("Unknown limit {}", key).into()),
+      }
+    }
+    Ok(limits)
+  }
+
+  /// An ionice priority, from 0 to 7.
+  fn io_level(level: &str) -> Result<u8, String> {
+    match level.parse() {
+      Ok(level) if level <= 7 => Ok(level),
+      _ => Err(format!(
+        "Invalid ionice priority {}, expected 0 to 7",
+        level
+      )),
+    }
+  }
+
+  /// Applies the rlimits and priorities to the calling process. Runs between
+  /// fork and exec, so it sticks to async-signal-safe calls.
+  pub fn apply(&self) -> io::Result<()> {
+    if let Some(secs) = self.cpu_time {
+      // SIGXCPU at the soft limit is what tells the violation apart.
+      setrlimit(Resource::RLIMIT_CPU, secs, secs.saturating_add(1))?;
+    }
+    for (resource, limit) in [
+      (Resource::RLIMIT_AS, self.address_space),
+      (Resource::RLIMIT_NOFILE, self.open_files),
+      (Resource::RLIMIT_NPROC, self.processes),
+    ] {
+      if let Some(limit) = limit {
+        setrlimit(resource, limit, limit)?;
+      }
+    }
+    if let Some(nice) = self.nice {
+      Errno::result(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
     }
+    if let Some(ionice) = &self.ionice {
+      let (class, level) = match ionice {
+        IoNice::Realtime(level) => (1, *level),
+        IoNice::BestEffort(level) => (2, *level),
+        IoNice::Idle => (3, 0),
+      };
+      // glibc has no wrapper for it.
+      Errno::result(unsafe {
+        libc::syscall(
+          libc::SYS_ioprio_set,
+          Self::IOPRIO_WHO_PROCESS,
+          0,
+          class << 13 | level as libc::c_long,
+        )
+      })?;
+    }
+    Ok(())
+  }
+
+  /// Whether some limits need the run's cgroup.
+  pub fn need_cgroup(&self) -> bool {
+    self.memory.is_some() || self.cpus.is_some() || self.pids.is_some()
   }
 }
 
-impl Execute {
-  pub fn from_args(args: &[String]) -> Option<Self> {
-    let mut execute = Self::default();
-    let mut hasarg = false;
-    for (index, arg) in args.iter().enumerate() {
-      match arg.as_str() {
-        "--exec" => {
-          execute.executable = PathBuf::from(match garg::<String>(args, index + 1) {
-            Some(data) => data,
-            None => continue,
-          });
-          hasarg = true;
+impl Sandbox {
+  /// Reads options like `private_tmp read_only_paths=/etc,/usr seccomp=deny:socket,bind`.
+  pub fn from_arg(arg: &str) -> Result<Self, Box<dyn Error>> {
+    let mut sandbox = Self::default();
+    let list = |value: &str| value.split(',').map(|item| item.to_string()).collect();
+    for option in arg.split_whitespace() {
+      match option.split_once('=') {
+        None if option == "private_tmp" => sandbox.private_tmp = true,
+        None if option == "no_new_privileges" => sandbox.no_new_privileges = true,
+        None if option == "private_network" => sandbox.private_network = true,
+        Some(("read_only_paths", paths)) => {
+          sandbox.read_only_paths = list(paths);
+          if let Some(path) = sandbox
+            .read_only_paths
+            .iter()
+            .find(|path| !path.starts_with('/'))
+          {
+            return Err(format!("Read-only path {:?} is not absolute", path).into());
+          }
         }
-        "--env" => {
-          execute.env = Some(
-            (match garg::<String>(args, index + 1) {
-              Some(data) => data,
-              None => continue,
-            })
-            .split_whitespace()
-            .filter_map(|pair| pair.split_once("="))
-            .map(|(a, b)| (a.to_string(), b.to_string()))
-            .collect(),
-          );
+        Some(("capabilities", caps)) => {
+          let caps = match caps {
+            "" => Vec::new(),
+            caps => list(caps),
+          };
+          sandbox::capability_mask(&caps)?;
+          sandbox.capabilities = Some(caps);
         }
-        "--args" => {
-          execute.args = match garg::<String>(args, index + 1) {
-            Some(data) => Some(data.split(' ').map(|s| s.to_string()).collect()),
-            None => None,
+        Some(("seccomp", "system")) => sandbox.seccomp = Some(Seccomp::System),
+        Some(("seccomp", profile)) => {
+          let calls: Vec<String> = match profile.strip_prefix("deny:") {
+            Some(calls) => list(calls),
+            None => {
+              return Err(
+                format!(
+                  "Invalid seccomp {}, expected system or deny:CALL,...",
+                  profile
+                )
+                .into(),
+              )
+            }
+          };
+          for call in &calls {
+            sandbox::system_call(call)?;
           }
+          sandbox.seccomp = Some(Seccomp::Deny(calls));
         }
-        "--dir" => execute.working_dir = garg(args, index + 1),
-        "--username" => execute.user = SystemUser::from_un(garg(args, index + 1)),
-        _ => (),
+        _ => return Err(format!("Unknown sandbox option {}", option).into()),
       }
     }
-    if hasarg {
-      Some(execute)
-    } else {
-      None
-    }
-  }
-
-  pub fn exec(&self) -> Result<u32, Box<dyn Error>> {
-    let child = process::Command::new(&self.executable)
-      .args(self.args.clone().unwrap_or(vec![]))
-      .envs(self.env.clone().unwrap_or(HashMap::new()))
-      .current_dir(self.working_dir.clone().unwrap_or("/tmp".into()))
-      .spawn()?;
-    Ok(child.id())
+    Ok(sandbox)
   }
 }
 
 impl Action {
-  pub fn from_args(args: &[String]) -> Self {
-    let mut action = Self::default();
-    let mut hasarg = false;
-    for arg in args {
-      match arg.as_str() {
-        "--exec" => {
-          action = Action::Exec(match Execute::from_args(args) {
-            Some(data) => data,
-            None => continue,
-          });
-          hasarg = true;
+  pub fn from_args(args: &[String]) -> Result<Self, Box<dyn Error>> {
+    let exec = Execute::from_args(args)?;
+    for (index, arg) in args.iter().enumerate() {
+      if arg == "--script" {
+        if let Some(source) = garg(args, index + 1) {
+          return Ok(Self::Script(Script {
+            source,
+            input: match args.iter().any(|arg| arg == "--script-stdin") {
+              true => ScriptInput::Stdin,
+              false => ScriptInput::File,
+            },
+            exec,
+          }));
         }
-        "--some" => (),
-        _ => (),
       }
     }
-    if hasarg {
-      action
-    } else {
-      Self::None
+    Ok(match exec.executable.as_os_str().is_empty() {
+      true => Self::None,
+      false => Self::Exec(exec),
+    })
+  }
+
+  /// What to spawn for a run of the action. A script may need a file, which
+  /// the run then owns.
+  pub fn prepare(
+    &self,
+    run: Option<&RunEntry>,
+    run_id: u64,
+  ) -> Result<(Execute, Option<PathBuf>), Box<dyn Error>> {
+    let (exec, script) = match self {
+      Self::Exec(exec) => (exec, None),
+      Self::Script(script) => (&script.exec, Some(script)),
+      Self::None => return Err("Nothing to run".into()),
+    };
+    let mut exec = match run {
+      Some(run) => exec.with_overrides(run),
+      None => exec.clone(),
+    };
+    let script = match script {
+      Some(script) => script,
+      None => return Ok((exec, None)),
+    };
+    if exec.executable.as_os_str().is_empty() {
+      exec.executable = PathBuf::from("/bin/sh");
+    }
+    match script.input {
+      ScriptInput::Stdin => {
+        if exec.stdin.is_some() {
+          return Err("A script read from stdin can't have stdin of its own".into());
+        }
+        exec.stdin = Some(script.source.clone());
+        Ok((exec, None))
+      }
+      ScriptInput::File => {
+        let path = output_dir().join(format!("{}.script", run_id));
+        if exec
+          .sandbox
+          .as_ref()
+          .is_some_and(|sandbox| sandbox.private_tmp)
+          && (path.starts_with("/tmp") || path.starts_with("/var/tmp"))
+        {
+          return Err(
+            "A script in a file would be hidden by private_tmp, read it from stdin".into(),
+          );
+        }
+        create_output_file(&path)?.write_all(script.source.as_bytes())?;
+        let mut args = vec![path.to_string_lossy().into_owned()];
+        args.extend(exec.args.take().unwrap_or_default());
+        exec.args = Some(args);
+        Ok((exec, Some(path)))
+      }
     }
   }
 }
 
 impl SystemUser {
   pub fn from_un(un: Option<String>) -> Option<Self> {
-    let un = match un {
-      Some(data) => data,
-      None => return None,
-    };
+    let un = un?;
     let info = sysinfo::System::new_with_specifics(sysinfo::RefreshKind::new().with_users_list());
     for user in info.users().iter() {
       if user.name() == un {
@@ -776,90 +2598,328 @@ impl SystemUser {
 }
 
 impl Work {
+  const KEPT_FINISHED_RUNS: usize = 16;
+
+  pub fn new(entry: Entry) -> Self {
+    Self {
+      status: entry.status,
+      trigger_state: TriggerState::from_entry(&entry),
+      entry,
+      running_processes: Vec::new(),
+      queued_runs: VecDeque::new(),
+      finished_runs: VecDeque::new(),
+    }
+  }
+
+  pub fn is_running(&self) -> bool {
+    matches!(self.status, Status::Running) || !self.running_processes.is_empty()
+  }
+
+  pub fn pause_expired(&self) -> bool {
+    match (&self.status, &self.entry.paused_until) {
+      (Status::Paused, Some(until)) => until.is_up(),
+      _ => false,
+    }
+  }
+
+  pub fn resume(&mut self) {
+    info!("Info: Resuming entry: {}", self.entry.name);
+    self.entry.status = Status::Pending;
+    self.entry.paused_until = None;
+    self.set_status(if self.running_processes.is_empty() {
+      Status::Pending
+    } else {
+      Status::Running
+    });
+    self.trigger_state.reschedule(&self.entry);
+  }
+
+  pub fn set_status(&mut self, status: Status) {
+    if self.status != status {
+      events::publish(Event::new(
+        EventKind::StatusChanged {
+          from: self.status,
+          to: status,
+        },
+        Some(&self.entry),
+      ));
+      self.status = status;
+    }
+  }
+
+  pub fn skip_next(&mut self) -> Result<(), Box<dyn Error>> {
+    info!("Info: Skipping next run of entry: {}", self.entry.name);
+    let exec_time = match self.trigger_state.exec_time.clone() {
+      Some(data) => data,
+      None => return Err(format!("Entry {} has no scheduled run", self.entry.name).into()),
+    };
+    match &self.entry.trigger {
+      Trigger::Timer(Timer::Repeat(timer)) | Trigger::Timer(Timer::ManyTimes(timer, _)) => {
+        self.trigger_state.exec_time = match exec_time + timer.clone() {
+          Some(data) => Some(data),
+          None => return Err("Error: Invalid time".into()),
+        };
+        self.trigger_state.update_effective_time(&self.entry);
+      }
+      _ => self.trigger_state.finish(),
+    }
+    Ok(())
+  }
+
+  /// Applies `do_if_running` to a run asked for, `false` if the entry got
+  /// stopped instead.
+  pub fn make_room(&mut self) -> Result<bool, Box<dyn Error>> {
+    if !self.is_running() {
+      return Ok(true);
+    }
+    match self.entry.do_if_running {
+      DoIfRunning::Continue => Err(format!("Entry {} is already running", self.entry.name).into()),
+      DoIfRunning::Stop => self.stop().map(|_| false),
+      DoIfRunning::Restart => self.kill_processes().map(|_| true),
+      DoIfRunning::StartNew => Ok(true),
+    }
+  }
+
+  /// Queued runs only overlap a running one if the entry starts new ones.
+  pub fn can_start_queued(&self) -> bool {
+    !self.is_running() || matches!(self.entry.do_if_running, DoIfRunning::StartNew)
+  }
+
+  /// Runs the entry now, `requested` is when the run was asked for. The caller
+  /// applies `do_if_running` first.
+  pub fn run(&mut self, run: &RunEntry, requested: &DateTime) -> Result<(), Box<dyn Error>> {
+    info!("Info: Running entry: {}", self.entry.name);
+    self.spawn(Some(run), requested)
+  }
+
   pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
     info!("Info: Starting entry: {}", self.entry.name);
-    match &self.entry.action {
-      Action::Exec(execute) => {
-        match self.entry.trigger.clone() {
-          Trigger::Timer(timer) => match timer {
-            Timer::Repeat(timer) => {
-              self.trigger_state.exec_time =
-                match self.trigger_state.exec_time.clone().unwrap() + timer {
-                  Some(data) => Some(data),
-                  None => return Err("Error: Invalid time".into()),
-                };
-              self.trigger_state.exec_times += 1;
-              execute.exec()?;
-            }
-            Timer::Once(_) => {
-              if self.trigger_state.exec_times >= 1 {
-                return Err(
-                  format!(
-                    "Error: Entry {} with Once timer executed twice!",
-                    self.entry.name
-                  )
-                  .into(),
-                );
-              }
-              self.trigger_state.exec_times += 1;
-              self.status = Status::Paused;
-              execute.exec()?;
-            }
-            Timer::ManyTimes(timer, times) => {
-              if self.trigger_state.exec_times >= times {
-                return Err(
-                  format!(
-                    "Error: Entry {} with ManyTimes timer executed exceeded times!",
-                    self.entry.name
-                  )
-                  .into(),
-                );
-              }
-              self.trigger_state.exec_time =
-                match self.trigger_state.exec_time.clone().unwrap() + timer {
-                  Some(data) => Some(data),
-                  None => return Err("Error: Invalid time".into()),
-                };
-              self.trigger_state.exec_times += 1;
-              execute.exec()?;
-            }
-            Timer::Never => {
-              return Err(
-                format!(
-                  "Error: Entry with a Never Timer executed! Entry: {}",
-                  self.entry.name
-                )
-                .into(),
+    let scheduled = self
+      .trigger_state
+      .effective_exec_time
+      .clone()
+      .unwrap_or_else(DateTime::now);
+    self.advance_trigger()?;
+    self.spawn(None, &scheduled)?;
+    info!("Info: Started entry: {}", self.entry.name);
+    Ok(())
+  }
+
+  /// Consumes the due occurrence of the timer without spawning anything.
+  pub fn advance_trigger(&mut self) -> Result<(), Box<dyn Error>> {
+    match self.entry.trigger.clone() {
+      Trigger::Timer(timer) => match timer {
+        Timer::Repeat(timer) => {
+          self.trigger_state.exec_time = match self.trigger_state.exec_time.clone().unwrap() + timer
+          {
+            Some(data) => Some(data),
+            None => return Err("Error: Invalid time".into()),
+          };
+          self.trigger_state.update_effective_time(&self.entry);
+          self.trigger_state.exec_times += 1;
+        }
+        Timer::Once(_) => {
+          if self.trigger_state.exec_times >= 1 {
+            return Err(
+              format!(
+                "Error: Entry {} with Once timer executed twice!",
+                self.entry.name
               )
-            }
-          },
-          Trigger::None => {
-            error!("Error: Entry {} executed without trigger!", self.entry.name)
+              .into(),
+            );
           }
+          self.trigger_state.exec_times += 1;
+          self.trigger_state.finish();
         }
-        self.status = Status::Running;
+        Timer::ManyTimes(timer, times) => {
+          if self.trigger_state.exec_times >= times {
+            return Err(
+              format!(
+                "Error: Entry {} with ManyTimes timer executed exceeded times!",
+                self.entry.name
+              )
+              .into(),
+            );
+          }
+          self.trigger_state.exec_time = match self.trigger_state.exec_time.clone().unwrap() + timer
+          {
+            Some(data) => Some(data),
+            None => return Err("Error: Invalid time".into()),
+          };
+          self.trigger_state.update_effective_time(&self.entry);
+          self.trigger_state.exec_times += 1;
+          if self.trigger_state.exec_times >= times {
+            self.trigger_state.finish();
+          }
+        }
+        Timer::Never => {
+          return Err(
+            format!(
+              "Error: Entry with a Never Timer executed! Entry: {}",
+              self.entry.name
+            )
+            .into(),
+          )
+        }
+      },
+      Trigger::None => {
+        error!("Error: Entry {} executed without trigger!", self.entry.name)
       }
-      Action::None => (),
     }
-    info!("Info: Started entry: {}", self.entry.name);
     Ok(())
   }
+
+  /// Starts a run, `scheduled` is when it was due or asked for.
+  fn spawn(&mut self, run: Option<&RunEntry>, scheduled: &DateTime) -> Result<(), Box<dyn Error>> {
+    if let Action::None = self.entry.action {
+      return Ok(());
+    }
+    let run_id = next_run_id();
+    let output = self.entry.logger.output_file(run_id);
+    let run_vars = [
+      ("RTODO_ENTRY_ID", self.entry.id.to_string()),
+      ("RTODO_ENTRY_NAME", self.entry.name.clone()),
+      ("RTODO_RUN_ID", run_id.to_string()),
+      ("RTODO_SCHEDULED_TIME", scheduled.to_rfc3339()),
+    ];
+    // Other runs of an entry logging to a file of its own wrote what is there.
+    let output_start = output
+      .as_ref()
+      .and_then(|path| fs::metadata(path).ok())
+      .map_or(0, |meta| meta.len());
+    let started = self
+      .entry
+      .action
+      .prepare(run, run_id)
+      .and_then(|(execute, script_file)| {
+        let limits = execute.limits.as_deref();
+        let started = execute.environment(&run_vars).and_then(|env| {
+          let cgroup = cgroup::create(run_id, limits)?;
+          match execute.exec(output.as_deref(), cgroup.as_deref(), &env) {
+            Ok(pid) => Ok((pid, cgroup)),
+            Err(err) => {
+              if let Some(cgroup) = &cgroup {
+                cgroup::remove(cgroup);
+              }
+              Err(err)
+            }
+          }
+        });
+        if let (Err(_), Some(path)) = (&started, &script_file) {
+          let _ = fs::remove_file(path);
+        }
+        let (pid, cgroup) = started?;
+        Ok(Process {
+          script_file,
+          output_start,
+          ..Process::new(pid, run_id, output, cgroup)
+        })
+      });
+    let process = started.inspect_err(|err| {
+      metrics::run_start_failed(&self.entry);
+      events::publish(Event::new(
+        EventKind::RunFailed {
+          run_id: Some(run_id),
+          pid: None,
+          reason: err.to_string(),
+        },
+        Some(&self.entry),
+      ))
+    })?;
+    let pid = process.pid;
+    self.running_processes.push(process);
+    metrics::run_started(&self.entry);
+    events::publish(Event::new(
+      EventKind::RunStarted { run_id, pid },
+      Some(&self.entry),
+    ));
+    if !matches!(self.status, Status::Paused) {
+      self.set_status(Status::Running);
+    }
+    Ok(())
+  }
+
+  pub fn queue(&mut self, run: RunEntry, seq: u64) {
+    info!("Info: Queued run of entry: {}", self.entry.name);
+    self.queued_runs.push_back(QueuedRun {
+      seq,
+      queued_at: DateTime::now(),
+      run,
+    });
+  }
+
   pub fn stop(&mut self) -> Result<(), Box<dyn Error>> {
     info!("Info: Stopping entry: {}", self.entry.name);
     match self.entry.action {
-      Action::Exec(_) => {
-        for i in &self.running_processes {
-          i.kill()?;
-        }
-        self.running_processes.clear();
-        self.status = Status::Paused;
+      Action::Exec(_) | Action::Script(_) => {
+        self.kill_processes()?;
+        self.set_status(Status::Paused);
         Ok(())
       }
       Action::None => Ok(()),
     }
   }
+
+  fn kill_processes(&mut self) -> Result<(), Box<dyn Error>> {
+    for i in &self.running_processes {
+      i.kill()?;
+    }
+    for process in std::mem::take(&mut self.running_processes) {
+      self.finish_run(process, Some("killed by rtodo".to_string()));
+    }
+    Ok(())
+  }
+
+  /// Records an exited run and publishes its outcome.
+  pub fn finish_run(&mut self, process: Process, error: Option<String>) {
+    let kind = match &error {
+      None => EventKind::RunFinished {
+        run_id: process.run_id,
+        pid: process.pid,
+      },
+      Some(reason) => EventKind::RunFailed {
+        run_id: Some(process.run_id),
+        pid: Some(process.pid),
+        reason: reason.clone(),
+      },
+    };
+    events::publish(Event::new(kind, Some(&self.entry)));
+    metrics::run_finished(&self.entry, &process, error.is_none());
+    let mut limits_hit = Vec::new();
+    if matches!(&process.exit, Some(Err(reason)) if reason.ends_with("SIGXCPU")) {
+      limits_hit.push("CPU time limit".to_string());
+    }
+    if let Some(cgroup) = &process.cgroup {
+      limits_hit.extend(cgroup::limits_hit(cgroup));
+      cgroup::remove(cgroup);
+    }
+    if let Some(path) = &process.script_file {
+      let _ = fs::remove_file(path);
+    }
+    if !limits_hit.is_empty() {
+      warn!(
+        "Warn: Run {} of entry {} hit its {}",
+        process.run_id,
+        self.entry.name,
+        limits_hit.join(", ")
+      );
+    }
+    self.finished_runs.push_back(FinishedRun {
+      output_end: process.output_size(),
+      process,
+      finished_at: DateTime::now(),
+      error,
+      limits_hit,
+    });
+    while self.finished_runs.len() > Self::KEPT_FINISHED_RUNS {
+      if let Some(run) = self.finished_runs.pop_front() {
+        run.process.remove_output();
+      }
+    }
+  }
+
   pub fn restart(&mut self) -> Result<(), Box<dyn Error>> {
-    self.stop()?;
+    self.kill_processes()?;
     self.start()
   }
 }
@@ -893,21 +2953,70 @@ impl CommandHelp for DoIfRunning {
 }
 
 impl Process {
+  pub fn new(
+    pid: u32,
+    run_id: u64,
+    output_tmp_file: Option<PathBuf>,
+    cgroup: Option<PathBuf>,
+  ) -> Self {
+    Self {
+      pid: pid as i32,
+      run_id,
+      started_at: DateTime::now(),
+      output_tmp_file,
+      output_start: 0,
+      cgroup,
+      exit: None,
+      script_file: None,
+    }
+  }
+
+  /// Current size of the output file, `None` if the run has none.
+  pub fn output_size(&self) -> Option<u64> {
+    let path = self.output_tmp_file.as_ref()?;
+    Some(fs::metadata(path).map_or(0, |meta| meta.len()))
+  }
+
+  /// Deletes the captured output, unless it went to a file the user chose.
+  pub fn remove_output(&self) {
+    if let Some(path) = &self.output_tmp_file {
+      if path.starts_with(output_dir()) {
+        let _ = fs::remove_file(path);
+      }
+    }
+  }
+
+  /// Signals every process of the run, its process group along with the
+  /// processes of its cgroup that left the group.
+  pub fn signal(&self, signal: Signal) -> Result<(), Box<dyn Error>> {
+    if let Some(cgroup) = &self.cgroup {
+      cgroup::signal(cgroup, signal)?;
+    }
+    match kill(Pid::from_raw(-self.pid), signal) {
+      Ok(_) | Err(nix::Error::ESRCH) => Ok(()),
+      Err(err) => Err(err.into()),
+    }
+  }
+
   pub fn kill(&self) -> Result<(), Box<dyn Error>> {
-    process::Command::new("kill")
-      .arg("-9")
-      .arg(self.pid.to_string())
-      .spawn()?;
-    Ok(())
+    self.signal(Signal::SIGKILL)
+  }
+
+  /// Whether a process of the run is still alive after its first one exited,
+  /// e.g. one a shell script started in the background.
+  pub fn is_lingering(&self) -> bool {
+    match &self.cgroup {
+      Some(cgroup) => cgroup::is_populated(cgroup),
+      None => kill(Pid::from_raw(-self.pid), None).is_ok(),
+    }
   }
 }
 
 impl Status {
   pub fn from_args(args: &[String]) -> Self {
     for arg in args {
-      match arg.as_str() {
-        "--paused" => return Self::Paused,
-        _ => (),
+      if arg.as_str() == "--paused" {
+        return Self::Paused;
       }
     }
     Self::default()
@@ -926,10 +3035,10 @@ impl Trigger {
 
 impl TriggerState {
   pub fn from_entry(entry: &Entry) -> Self {
-    match &entry.trigger {
+    let mut state = match &entry.trigger {
       Trigger::Timer(timer) => match timer {
         Timer::Repeat(timer) => Self {
-          exec_time: match DateTime::from_duration(&timer) {
+          exec_time: match DateTime::from_duration(timer) {
             Some(data) => Some(data),
             None => {
               error!(
@@ -939,10 +3048,10 @@ impl TriggerState {
               None
             }
           },
-          exec_times: 0,
+          ..Default::default()
         },
         Timer::ManyTimes(timer, _) => Self {
-          exec_time: match DateTime::from_duration(&timer) {
+          exec_time: match DateTime::from_duration(timer) {
             Some(data) => Some(data),
             None => {
               error!(
@@ -952,15 +3061,380 @@ impl TriggerState {
               None
             }
           },
-          exec_times: 0,
+          ..Default::default()
         },
+        // Recomputed, configs written before the timestamp was filled in have 0.
         Timer::Once(timer) => Self {
-          exec_time: Some(timer.clone()),
-          exec_times: 0,
+          exec_time: DateTime::from_ymd_hms(
+            timer.year,
+            timer.month,
+            timer.day,
+            timer.hour,
+            timer.min,
+            timer.sec,
+          ),
+          ..Default::default()
         },
         Timer::Never => Self::default(),
       },
       Trigger::None => Self::default(),
+    };
+    state.update_effective_time(entry);
+    state
+  }
+
+  /// Recomputes the next run from now, keeping the run count so that
+  /// exhausted Once and ManyTimes timers stay exhausted.
+  pub fn reschedule(&mut self, entry: &Entry) {
+    let exec_times = self.exec_times;
+    *self = Self::from_entry(entry);
+    self.exec_times = exec_times;
+    match &entry.trigger {
+      Trigger::Timer(Timer::Once(_)) if exec_times >= 1 => self.finish(),
+      Trigger::Timer(Timer::ManyTimes(_, times)) if exec_times >= *times => self.finish(),
+      _ => (),
+    }
+  }
+
+  pub fn finish(&mut self) {
+    self.exec_time = None;
+    self.effective_exec_time = None;
+  }
+
+  pub fn update_effective_time(&mut self, entry: &Entry) {
+    self.delay = entry.schedule_delay();
+    self.effective_exec_time = match &self.exec_time {
+      Some(exec_time) => exec_time.clone() + Duration::from_sec(self.delay),
+      None => None,
+    };
+  }
+
+  pub fn is_up(&self) -> bool {
+    match &self.effective_exec_time {
+      Some(exec_time) => exec_time.is_up(),
+      None => false,
+    }
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::scheduler;
+  use nix::sys::wait::{waitpid, WaitStatus};
+
+  /// A core with `entries`, writing its config to a file of its own.
+  pub(crate) fn rtodo(name: &str, entries: Vec<Entry>) -> Rtodo {
+    static OUTPUT_DIR: std::sync::Once = std::sync::Once::new();
+    OUTPUT_DIR.call_once(|| {
+      let path = env::temp_dir().join(format!("rtodo-test-{}-runs", std::process::id()));
+      init_output_dir(&path).unwrap();
+    });
+    let mut config: Config =
+      serde_json::from_str(r#"{"address":"127.0.0.1:0","token":"secret"}"#).unwrap();
+    config.entries = entries;
+    Rtodo {
+      works: config.entries.iter().cloned().map(Work::new).collect(),
+      cur_entry_id: config.entries.len() as u32,
+      config,
+      conf_path: env::temp_dir()
+        .join(format!("rtodo-test-{}-{}.conf", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned(),
+      server_pid: 0,
+      daemon_status: RtodoDaemonStatus::Running,
+      rcli: reqwest::blocking::Client::new(),
+      api_url: String::new(),
+      queue_seq: AtomicU64::new(0),
+    }
+  }
+
+  pub(crate) fn entry(id: u32, name: &str) -> Entry {
+    let mut entry = Entry::new(
+      Trigger::None,
+      Logger::Off,
+      Action::None,
+      DoIfRunning::default(),
+      Status::Pending,
+    );
+    entry.id = id;
+    entry.name = String::from(name);
+    entry
+  }
+
+  /// An entry running `sleep 30` in a session of its own.
+  fn sleeper(id: u32, name: &str) -> Entry {
+    let mut entry = entry(id, name);
+    entry.action =
+      serde_json::from_str(r#"{"Exec":{"executable":"/bin/sleep","args":["30"]}}"#).unwrap();
+    entry
+  }
+
+  fn run(name: &str) -> RunEntry {
+    RunEntry {
+      entry: EntryIdentifier::Name(String::from(name)),
+      args: None,
+      env: None,
+    }
+  }
+
+  /// Kills the oldest run of the work and records its end like the daemon.
+  fn end_run(work: &mut Work) -> WaitStatus {
+    let process = work.running_processes.remove(0);
+    process.kill().unwrap();
+    let status = waitpid(Pid::from_raw(process.pid), None).unwrap();
+    work.finish_run(process, None);
+    if work.running_processes.is_empty() {
+      work.set_status(Status::Pending);
+    }
+    status
+  }
+
+  fn end_runs(rtodo: &mut Rtodo) {
+    for work in rtodo.works.iter_mut() {
+      while !work.running_processes.is_empty() {
+        end_run(work);
+      }
+    }
+  }
+
+  fn token(rtodo: &mut Rtodo, scope: TokenScope, entries: &[&str], tags: &[&str]) -> String {
+    let secret = rtodo
+      .create_token(NewToken {
+        name: format!("{:?}", scope),
+        scope,
+        entries: entries.iter().map(|name| String::from(*name)).collect(),
+        tags: tags.iter().map(|tag| String::from(*tag)).collect(),
+      })
+      .unwrap();
+    fs::remove_file(&rtodo.conf_path).unwrap();
+    secret
+  }
+
+  #[test]
+  fn read_tokens_cannot_operate_or_administer() {
+    let mut rtodo = rtodo("read-token", vec![entry(1, "a")]);
+    let secret = token(&mut rtodo, TokenScope::Read, &[], &[]);
+    assert!(rtodo.authenticate("wrong").is_none());
+    let grant = rtodo.authorize(&secret, TokenScope::Read).unwrap();
+    assert!(grant.require_all(TokenScope::Read).is_ok());
+    assert!(grant.require_all(TokenScope::Operate).is_err());
+    assert!(grant.require_all(TokenScope::Admin).is_err());
+    assert!(rtodo.authorize(&secret, TokenScope::Operate).is_err());
+    let run = EntryIdentifier::Name(String::from("a"));
+    assert!(rtodo.check_access(&grant, TokenScope::Read, &run).is_ok());
+    assert!(rtodo
+      .check_access(&grant, TokenScope::Operate, &run)
+      .is_err());
+  }
+
+  #[test]
+  fn limited_tokens_are_forbidden_on_other_entries() {
+    let mut nightly = entry(2, "b");
+    nightly.tags = vec![String::from("nightly")];
+    let mut rtodo = rtodo("limited-token", vec![entry(1, "a"), nightly, entry(3, "c")]);
+    let secret = token(&mut rtodo, TokenScope::Operate, &["a"], &["nightly"]);
+    let grant = rtodo.authorize(&secret, TokenScope::Operate).unwrap();
+    let check = |identifier: EntryIdentifier| {
+      rtodo
+        .check_access(&grant, TokenScope::Operate, &identifier)
+        .map_err(ApiError::forbidden)
+    };
+    assert!(check(EntryIdentifier::Name(String::from("a"))).is_ok());
+    assert!(check(EntryIdentifier::Id(2)).is_ok());
+    let err = check(EntryIdentifier::Name(String::from("c"))).unwrap_err();
+    assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+    assert!(rtodo.covers(&grant, 1));
+    assert!(!rtodo.covers(&grant, 3));
+    assert!(grant.require_all(TokenScope::Read).is_err());
+  }
+
+  #[test]
+  fn max_instances_queues_runs_until_one_ends() {
+    let mut entry = sleeper(1, "a");
+    entry.max_instances = Some(1);
+    let mut rtodo = rtodo("max-instances", vec![entry]);
+    assert!(matches!(
+      rtodo.run_entry(&run("a")),
+      Ok(RunOutcome::Started)
+    ));
+    match rtodo.run_entry(&run("a")) {
+      Ok(RunOutcome::Queued { reason }) => assert_eq!(reason, "entry reached max_instances 1"),
+      _ => panic!("the second run is not queued"),
+    }
+    rtodo.start_queued_runs(&mut rtodo.job_counts());
+    assert_eq!(rtodo.works[0].running_processes.len(), 1);
+    assert_eq!(rtodo.works[0].queued_runs.len(), 1);
+    end_run(&mut rtodo.works[0]);
+    rtodo.start_queued_runs(&mut rtodo.job_counts());
+    assert_eq!(rtodo.works[0].running_processes.len(), 1);
+    assert!(rtodo.works[0].queued_runs.is_empty());
+    end_runs(&mut rtodo);
+  }
+
+  #[test]
+  fn overflowing_runs_are_dropped_by_policy_or_on_a_full_queue() {
+    let mut dropping = sleeper(1, "a");
+    dropping.max_instances = Some(1);
+    dropping.overflow_policy = OverflowPolicy::Drop;
+    let mut queueing = sleeper(2, "b");
+    queueing.max_instances = Some(1);
+    let mut rtodo = rtodo("overflow", vec![dropping, queueing]);
+    rtodo.config.max_queued_jobs = 1;
+    assert!(matches!(
+      rtodo.run_entry(&run("a")),
+      Ok(RunOutcome::Started)
+    ));
+    let err = rtodo.run_entry(&run("a")).err().unwrap();
+    assert!(err.to_string().starts_with("Dropped run of entry a"));
+    assert!(rtodo.works[0].queued_runs.is_empty());
+    assert!(matches!(
+      rtodo.run_entry(&run("b")),
+      Ok(RunOutcome::Started)
+    ));
+    assert!(matches!(
+      rtodo.run_entry(&run("b")),
+      Ok(RunOutcome::Queued { .. })
+    ));
+    let err = rtodo.run_entry(&run("b")).err().unwrap();
+    assert_eq!(err.to_string(), "Run queue is full, dropped run of entry b");
+    assert_eq!(rtodo.works[1].queued_runs.len(), 1);
+    end_runs(&mut rtodo);
+  }
+
+  #[test]
+  fn max_concurrent_jobs_queues_runs_of_other_entries() {
+    let mut rtodo = rtodo("max-concurrent", vec![sleeper(1, "a"), sleeper(2, "b")]);
+    rtodo.config.max_concurrent_jobs = Some(1);
+    assert!(matches!(
+      rtodo.run_entry(&run("a")),
+      Ok(RunOutcome::Started)
+    ));
+    match rtodo.run_entry(&run("b")) {
+      Ok(RunOutcome::Queued { reason }) => assert_eq!(reason, "max_concurrent_jobs 1 reached"),
+      _ => panic!("the run of b is not queued"),
+    }
+    end_run(&mut rtodo.works[0]);
+    rtodo.start_queued_runs(&mut rtodo.job_counts());
+    assert_eq!(rtodo.works[1].running_processes.len(), 1);
+    end_runs(&mut rtodo);
+  }
+
+  #[test]
+  fn restart_replaces_the_running_run() {
+    let mut entry = sleeper(1, "a");
+    entry.max_instances = Some(1);
+    entry.do_if_running = DoIfRunning::Restart;
+    let mut rtodo = rtodo("restart", vec![entry]);
+    assert!(matches!(
+      rtodo.run_entry(&run("a")),
+      Ok(RunOutcome::Started)
+    ));
+    let replaced = rtodo.works[0].running_processes[0].pid;
+    assert!(matches!(
+      rtodo.run_entry(&run("a")),
+      Ok(RunOutcome::Started)
+    ));
+    assert!(matches!(
+      waitpid(Pid::from_raw(replaced), None),
+      Ok(WaitStatus::Signaled(_, Signal::SIGKILL, _))
+    ));
+    let work = &rtodo.works[0];
+    assert_eq!(work.running_processes.len(), 1);
+    assert_ne!(work.running_processes[0].pid, replaced);
+    assert!(work.queued_runs.is_empty());
+    let killed = work.finished_runs.back().unwrap();
+    assert_eq!(killed.process.pid, replaced);
+    assert_eq!(killed.error.as_deref(), Some("killed by rtodo"));
+    end_runs(&mut rtodo);
+  }
+
+  #[test]
+  fn entries_sharing_a_lock_take_turns() {
+    let mut first = sleeper(1, "a");
+    first.locks = vec![String::from("db")];
+    let mut second = sleeper(2, "b");
+    second.locks = vec![String::from("db")];
+    let mut rtodo = rtodo("lock", vec![first, second]);
+    assert!(matches!(
+      rtodo.run_entry(&run("a")),
+      Ok(RunOutcome::Started)
+    ));
+    match rtodo.run_entry(&run("b")) {
+      Ok(RunOutcome::Queued { reason }) => assert_eq!(reason, "lock db is held"),
+      _ => panic!("the run of b is not queued"),
+    }
+    rtodo.start_queued_runs(&mut rtodo.job_counts());
+    assert!(rtodo.works[1].running_processes.is_empty());
+    let holders = &rtodo.get_locks()[0].holders;
+    assert_eq!(holders.len(), 1);
+    assert_eq!(holders[0].entry_name, "a");
+    end_run(&mut rtodo.works[0]);
+    rtodo.start_queued_runs(&mut rtodo.job_counts());
+    assert!(rtodo.works[0].running_processes.is_empty());
+    assert_eq!(rtodo.works[1].running_processes.len(), 1);
+    assert!(rtodo.works[1].queued_runs.is_empty());
+    assert!(matches!(
+      rtodo.run_entry(&run("a")),
+      Ok(RunOutcome::Queued { .. })
+    ));
+    end_runs(&mut rtodo);
+  }
+
+  fn every_minute(id: u32, name: &str) -> Entry {
+    let mut entry = entry(id, name);
+    entry.trigger = Trigger::Timer(Timer::Repeat(Duration::from_sec(60)));
+    entry
+  }
+
+  #[test]
+  fn expired_pauses_resume_the_entry() {
+    let mut rtodo = rtodo("pause", vec![every_minute(1, "a"), every_minute(2, "b")]);
+    let now = DateTime::now();
+    for (name, offset) in [("a", -5), ("b", 3600)] {
+      rtodo
+        .pause_entry(&PauseEntry {
+          entry: EntryIdentifier::Name(String::from(name)),
+          until: Some(DateTime {
+            timestamp: now.timestamp + offset,
+            ..now.clone()
+          }),
+        })
+        .unwrap();
     }
+    assert_eq!(
+      scheduler::due_time(&rtodo.works[0]),
+      Some(now.timestamp - 5)
+    );
+    assert!(rtodo.works[0].pause_expired());
+    assert!(!rtodo.works[1].pause_expired());
+    rtodo.resume_entry(&EntryIdentifier::Id(1)).unwrap();
+    let work = &rtodo.works[0];
+    assert!(matches!(work.status, Status::Pending));
+    assert!(work.entry.paused_until.is_none());
+    assert!(work.trigger_state.effective_exec_time.is_some());
+    let config: Config =
+      serde_json::from_str(&fs::read_to_string(&rtodo.conf_path).unwrap()).unwrap();
+    fs::remove_file(&rtodo.conf_path).unwrap();
+    assert!(matches!(config.entries[0].status, Status::Pending));
+    assert!(config.entries[0].paused_until.is_none());
+    assert!(matches!(config.entries[1].status, Status::Paused));
+  }
+
+  #[test]
+  fn skip_moves_the_next_run_one_occurrence_on() {
+    let mut rtodo = rtodo("skip", vec![every_minute(1, "a")]);
+    let state = rtodo.works[0].trigger_state.clone();
+    let due = state.effective_exec_time.as_ref().unwrap().timestamp;
+    rtodo
+      .skip_entry(&EntryIdentifier::Name(String::from("a")))
+      .unwrap();
+    let skipped = &rtodo.works[0].trigger_state;
+    assert_eq!(
+      skipped.effective_exec_time.as_ref().unwrap().timestamp,
+      due + 60
+    );
+    assert_eq!(skipped.exec_times, state.exec_times);
+    assert!(rtodo.works[0].running_processes.is_empty());
   }
 }
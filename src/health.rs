@@ -0,0 +1,119 @@
+use crate::types::*;
+
+use actix_web::HttpResponse;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::time::{timeout, Duration};
+
+/// A loop that hasn't finished a pass for this long is considered stuck.
+const STALE_AFTER: i64 = 10;
+const NEVER: i64 = i64::MIN;
+/// How long readiness waits for the core before reporting it as not answering.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+pub enum Loop {
+  Core,
+  Reaper,
+}
+
+static STARTED_AT: AtomicI64 = AtomicI64::new(NEVER);
+static HEARTBEATS: [AtomicI64; 2] = [AtomicI64::new(NEVER), AtomicI64::new(NEVER)];
+
+fn now() -> i64 {
+  chrono::Local::now().timestamp()
+}
+
+/// Marks the start of the daemon, loops get `STALE_AFTER` from here for their first beat.
+pub fn start() {
+  STARTED_AT.store(now(), Ordering::Relaxed);
+}
+
+pub fn started_at() -> i64 {
+  STARTED_AT.load(Ordering::Relaxed)
+}
+
+/// Called by a loop on each pass, busy or idle.
+pub fn beat(which: Loop) {
+  HEARTBEATS[which as usize].store(now(), Ordering::Relaxed);
+}
+
+fn loop_health(which: Loop) -> LoopHealth {
+  let last = HEARTBEATS[which as usize].load(Ordering::Relaxed);
+  let since = match last {
+    NEVER => STARTED_AT.load(Ordering::Relaxed),
+    last => last,
+  };
+  LoopHealth {
+    alive: since != NEVER && now().saturating_sub(since) <= STALE_AFTER,
+    last_heartbeat: (last != NEVER).then_some(last),
+  }
+}
+
+fn health() -> Health {
+  let core = loop_health(Loop::Core);
+  let reaper = loop_health(Loop::Reaper);
+  let status = match (core.alive, reaper.alive) {
+    (false, _) => "core is stuck",
+    (_, false) => "reaper is stuck",
+    _ => "ok",
+  };
+  Health {
+    status: status.to_string(),
+    core,
+    reaper,
+  }
+}
+
+/// Both loops are making progress.
+pub fn is_healthy() -> bool {
+  health().status == "ok"
+}
+
+fn respond(health: Health) -> HttpResponse {
+  match health.status.as_str() {
+    "ok" => HttpResponse::Ok(),
+    _ => HttpResponse::ServiceUnavailable(),
+  }
+  .json(health)
+}
+
+/// Doesn't go through the core, so that it still answers when the core is stuck.
+#[utoipa::path(
+  get,
+  path = "/healthz",
+  responses(
+    (status = 200, description = "The core and reaper are making progress", body = Health),
+    (status = 503, description = "A loop stopped making progress", body = Health)
+  )
+)]
+pub async fn healthz() -> HttpResponse {
+  respond(health())
+}
+
+#[utoipa::path(
+  get,
+  path = "/readyz",
+  responses(
+    (status = 200, description = "Both loops went over the works and the daemon isn't stopping", body = Health),
+    (status = 503, description = "Starting, stopping, unhealthy or the core not answering", body = Health)
+  )
+)]
+pub async fn readyz(state: RS) -> HttpResponse {
+  let mut health = health();
+  if health.status == "ok" {
+    let status = state.read(|rtodo| matches!(rtodo.daemon_status, RtodoDaemonStatus::Stopped));
+    let status = match timeout(READ_TIMEOUT, status).await {
+      // Not answering within the timeout, or gone, is what readiness is for.
+      Err(_) | Ok(Err(_)) => Some("core not answering"),
+      _ if health.core.last_heartbeat.is_none() || health.reaper.last_heartbeat.is_none() => {
+        Some("starting")
+      }
+      Ok(Ok(true)) => Some("stopping"),
+      Ok(Ok(false)) => None,
+    };
+    if let Some(status) = status {
+      health.status = String::from(status);
+    }
+  }
+  respond(health)
+}
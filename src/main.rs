@@ -1,17 +1,36 @@
 use log::{error, info};
 use std::env::args;
 use std::fs;
+use std::sync::atomic::AtomicU64;
 
+mod api_v1;
+mod audit;
+mod cgroup;
 mod daemon;
+mod events;
 mod funcs;
+mod health;
+mod metrics;
+mod openapi;
+mod runs;
+mod sandbox;
+mod scheduler;
 mod server;
+mod systemd;
+mod tls;
 mod types;
 mod utils;
 use types::*;
 
 fn main() {
-  env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
   let args: Vec<String> = args().collect();
+  let mut logger =
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+  // A detached daemon logs to a file.
+  if args.iter().any(|arg| arg == "--detach") {
+    logger.write_style(env_logger::WriteStyle::Never);
+  }
+  logger.init();
   let mut path = std::path::PathBuf::from("/etc/rtodo/rtodo.conf");
   if args.len() < 2 {
     println!("Usage: {} <operation> [args]", args[0]);
@@ -27,8 +46,8 @@ fn main() {
   let opt = match Operation::from_args(&args) {
     Ok(opt) => opt,
     Err(err) => {
-      err.source().unwrap();
-      return;
+      error!("Error: {}", err);
+      std::process::exit(1);
     }
   };
   let config_content = match fs::read(&path) {
@@ -69,21 +88,39 @@ fn main() {
           path.to_str().unwrap_or("Unknown"),
           err
         );
-        return ();
+        return;
       }
     }
   };
+  // Before the client for the daemon below spawns a thread, forking needs a
+  // single one.
+  if let Operation::StartDaemon(options) = &opt {
+    if let Err(err) = daemon::daemonize(options, &config, &path) {
+      error!("Error: {}", err);
+      std::process::exit(1);
+    }
+  }
   let cur_entry_id = config.entries.iter().map(|i| i.id).max().unwrap_or(0);
+  let (rcli, api_url) = match utils::daemon_client(&config) {
+    Ok((client, api_url)) => (client.build().unwrap(), api_url),
+    Err(err) => {
+      error!(
+        "Error: cannot set up the client for the daemon, Err: {}",
+        err
+      );
+      return;
+    }
+  };
   let mut rtodo = Rtodo {
     conf_path: path.to_str().unwrap().to_string(),
     works: Vec::new(),
     config,
     cur_entry_id,
-    executor_pid: -1,
-    checker_pid: -1,
     server_pid: -1,
     daemon_status: RtodoDaemonStatus::Running,
-    rcli: reqwest::blocking::Client::new(),
+    rcli,
+    api_url,
+    queue_seq: AtomicU64::new(0),
   };
   rtodo.init_works().unwrap();
   opt.handle(rtodo);
@@ -0,0 +1,312 @@
+use crate::types::*;
+use crate::utils::*;
+
+use actix_web::{http::Method, HttpRequest, HttpResponse};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// Upper bounds in seconds of the run duration buckets.
+const DURATION_BUCKETS: [f64; 12] = [
+  1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 21600.0, 86400.0,
+];
+/// Methods counted by name, see `count_request`.
+const METHODS: [Method; 9] = [
+  Method::GET,
+  Method::HEAD,
+  Method::POST,
+  Method::PUT,
+  Method::DELETE,
+  Method::CONNECT,
+  Method::OPTIONS,
+  Method::TRACE,
+  Method::PATCH,
+];
+const STATUSES: [Status; 4] = [
+  Status::Pending,
+  Status::Running,
+  Status::Paused,
+  Status::Error,
+];
+
+#[derive(Default)]
+struct EntryMetrics {
+  started: u64,
+  start_failures: u64,
+  succeeded: u64,
+  failed: u64,
+  /// Counts per bucket of `DURATION_BUCKETS`, not cumulative.
+  buckets: [u64; DURATION_BUCKETS.len()],
+  duration_sum: f64,
+  last_success: Option<i64>,
+}
+
+/// Counters since the daemon started, keyed by entry name so that they
+/// survive edits and reloads.
+#[derive(Default)]
+struct Metrics {
+  entries: HashMap<String, EntryMetrics>,
+  requests: HashMap<(String, String, u16), u64>,
+}
+
+fn metrics() -> MutexGuard<'static, Metrics> {
+  static METRICS: OnceLock<Mutex<Metrics>> = OnceLock::new();
+  METRICS
+    .get_or_init(Default::default)
+    .lock()
+    .unwrap_or_else(|err| err.into_inner())
+}
+
+pub fn run_started(entry: &Entry) {
+  metrics()
+    .entries
+    .entry(entry.name.clone())
+    .or_default()
+    .started += 1;
+}
+
+pub fn run_start_failed(entry: &Entry) {
+  metrics()
+    .entries
+    .entry(entry.name.clone())
+    .or_default()
+    .start_failures += 1;
+}
+
+pub fn run_finished(entry: &Entry, process: &Process, succeeded: bool) {
+  let now = DateTime::now().timestamp;
+  let duration = now.saturating_sub(process.started_at.timestamp).max(0) as f64;
+  let mut metrics = metrics();
+  let entry = metrics.entries.entry(entry.name.clone()).or_default();
+  if succeeded {
+    entry.succeeded += 1;
+    entry.last_success = Some(now);
+  } else {
+    entry.failed += 1;
+  }
+  if let Some(index) = DURATION_BUCKETS.iter().position(|bound| duration <= *bound) {
+    entry.buckets[index] += 1;
+  }
+  entry.duration_sum += duration;
+}
+
+/// `route` is the matched pattern rather than the path, so that entry names
+/// and run ids don't each get a series, nor made up methods.
+pub fn count_request(method: &Method, route: &str, status: u16) {
+  let method = match METHODS.contains(method) {
+    true => method.as_str(),
+    false => "other",
+  };
+  *metrics()
+    .requests
+    .entry((method.to_string(), route.to_string(), status))
+    .or_default() += 1;
+}
+
+fn escape(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Renders the Prometheus text format, gauges are read from `rtodo` as is.
+fn render(rtodo: &Rtodo) -> String {
+  let mut out = String::new();
+  render_runs(&mut out);
+  render_works(&mut out, rtodo);
+  render_requests(&mut out);
+  out
+}
+
+fn render_runs(out: &mut String) {
+  let metrics = metrics();
+  let mut entries: Vec<(&String, &EntryMetrics)> = metrics.entries.iter().collect();
+  entries.sort_by_key(|(name, _)| *name);
+
+  header(out, "rtodo_runs_started_total", "counter", "Runs started.");
+  for (name, entry) in entries.iter() {
+    let _ = writeln!(
+      out,
+      "rtodo_runs_started_total{{entry=\"{}\"}} {}",
+      escape(name),
+      entry.started
+    );
+  }
+  header(
+    out,
+    "rtodo_run_start_failures_total",
+    "counter",
+    "Runs whose process could not be spawned.",
+  );
+  for (name, entry) in entries.iter() {
+    let _ = writeln!(
+      out,
+      "rtodo_run_start_failures_total{{entry=\"{}\"}} {}",
+      escape(name),
+      entry.start_failures
+    );
+  }
+  header(
+    out,
+    "rtodo_runs_finished_total",
+    "counter",
+    "Runs finished, killed runs count as failed.",
+  );
+  for (name, entry) in entries.iter() {
+    for (outcome, count) in [("succeeded", entry.succeeded), ("failed", entry.failed)] {
+      let _ = writeln!(
+        out,
+        "rtodo_runs_finished_total{{entry=\"{}\",outcome=\"{}\"}} {}",
+        escape(name),
+        outcome,
+        count
+      );
+    }
+  }
+  header(
+    out,
+    "rtodo_run_duration_seconds",
+    "histogram",
+    "Duration of finished runs.",
+  );
+  for (name, entry) in entries.iter() {
+    let name = escape(name);
+    let mut cumulative = 0;
+    for (bound, count) in DURATION_BUCKETS.iter().zip(entry.buckets.iter()) {
+      cumulative += count;
+      let _ = writeln!(
+        out,
+        "rtodo_run_duration_seconds_bucket{{entry=\"{}\",le=\"{}\"}} {}",
+        name, bound, cumulative
+      );
+    }
+    let count = entry.succeeded + entry.failed;
+    let _ = writeln!(
+      out,
+      "rtodo_run_duration_seconds_bucket{{entry=\"{}\",le=\"+Inf\"}} {}",
+      name, count
+    );
+    let _ = writeln!(
+      out,
+      "rtodo_run_duration_seconds_sum{{entry=\"{}\"}} {}",
+      name, entry.duration_sum
+    );
+    let _ = writeln!(
+      out,
+      "rtodo_run_duration_seconds_count{{entry=\"{}\"}} {}",
+      name, count
+    );
+  }
+  header(
+    out,
+    "rtodo_last_success_timestamp_seconds",
+    "gauge",
+    "When the last successful run finished.",
+  );
+  for (name, entry) in entries.iter() {
+    if let Some(time) = entry.last_success {
+      let _ = writeln!(
+        out,
+        "rtodo_last_success_timestamp_seconds{{entry=\"{}\"}} {}",
+        escape(name),
+        time
+      );
+    }
+  }
+}
+
+fn render_works(out: &mut String, rtodo: &Rtodo) {
+  let works = &rtodo.works;
+  header(
+    out,
+    "rtodo_running_processes",
+    "gauge",
+    "Processes of enabled entries still running.",
+  );
+  for work in works.iter() {
+    let _ = writeln!(
+      out,
+      "rtodo_running_processes{{entry=\"{}\"}} {}",
+      escape(&work.entry.name),
+      work.running_processes.len()
+    );
+  }
+  header(out, "rtodo_works", "gauge", "Enabled entries by status.");
+  for status in STATUSES {
+    let count = works.iter().filter(|work| work.status == status).count();
+    let _ = writeln!(out, "rtodo_works{{status=\"{:?}\"}} {}", status, count);
+  }
+  header(
+    out,
+    "rtodo_next_run_timestamp_seconds",
+    "gauge",
+    "When the next scheduled run is due, including jitter and splay.",
+  );
+  for work in works.iter() {
+    if let Some(time) = &work.trigger_state.effective_exec_time {
+      let _ = writeln!(
+        out,
+        "rtodo_next_run_timestamp_seconds{{entry=\"{}\"}} {}",
+        escape(&work.entry.name),
+        time.timestamp
+      );
+    }
+  }
+}
+
+fn render_requests(out: &mut String) {
+  header(
+    out,
+    "rtodo_api_requests_total",
+    "counter",
+    "API requests by route and response status.",
+  );
+  let metrics = metrics();
+  let mut requests: Vec<_> = metrics.requests.iter().collect();
+  requests.sort();
+  for ((method, route, status), count) in requests {
+    let _ = writeln!(
+      out,
+      "rtodo_api_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+      escape(method),
+      escape(route),
+      status,
+      count
+    );
+  }
+}
+
+#[utoipa::path(
+  get,
+  path = "/metrics",
+  responses(
+    (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+    (status = 401, description = "Missing or invalid token, unless metrics are public", body = ApiError),
+    (status = 403, description = "The token is limited to some entries", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+pub async fn get_metrics(req: HttpRequest, state: RS) -> Result<HttpResponse, ApiError> {
+  let token = api_token(&req, None);
+  let body = state
+    .read(move |rtodo| {
+      if !rtodo.config.metrics.public {
+        check_request_token(token.as_deref(), rtodo, TokenScope::Read)?
+          .require_all(TokenScope::Read)
+          .map_err(ApiError::forbidden)?;
+      }
+      Ok::<String, ApiError>(render(rtodo))
+    })
+    .await??;
+  Ok(
+    HttpResponse::Ok()
+      .content_type("text/plain; version=0.0.4; charset=utf-8")
+      .body(body),
+  )
+}
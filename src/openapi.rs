@@ -0,0 +1,124 @@
+use crate::api_v1;
+use crate::audit;
+use crate::events;
+use crate::health;
+use crate::metrics;
+use crate::runs;
+use crate::server;
+
+use actix_web::{web, Responder};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+  fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+    openapi
+      .components
+      .get_or_insert_with(Default::default)
+      .add_security_scheme(
+        "bearer",
+        SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+      );
+  }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+  info(title = "rtodo", description = "Rtodo daemon API"),
+  paths(
+    server::hello,
+    server::api_index,
+    openapi_json,
+    server::validate_token,
+    server::get_entries,
+    server::get_works,
+    server::get_locks,
+    server::add_entries,
+    server::delete_entries,
+    server::edit_entry,
+    server::run_entry,
+    server::pause_entry,
+    server::resume_entry,
+    server::skip_entry,
+    server::reload_config,
+    server::create_token,
+    server::list_tokens,
+    server::revoke_token,
+    audit::get_audit,
+    events::sse,
+    events::websocket,
+    runs::get_runs,
+    runs::run_log,
+    server::stop_daemon,
+    metrics::get_metrics,
+    health::healthz,
+    health::readyz,
+    api_v1::get_entries,
+    api_v1::create_entry,
+    api_v1::get_entry,
+    api_v1::replace_entry,
+    api_v1::patch_entry,
+    api_v1::delete_entry,
+    api_v1::get_works,
+    api_v1::get_status,
+  ),
+  modifiers(&BearerAuth)
+)]
+pub struct ApiDoc;
+
+#[utoipa::path(get, path = "/api/openapi.json", responses((status = 200, description = "This document")))]
+pub async fn openapi_json() -> impl Responder {
+  web::Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Extracts `(path, method)` pairs from the calls to `pattern` in `source`,
+  /// e.g. `.route("/path", web::post()...)` or `web::resource("/path").route(web::get()...)`.
+  fn routes(source: &str, pattern: &str, prefix: &str) -> Vec<(String, String)> {
+    source
+      .split(pattern)
+      .skip(1)
+      .filter_map(|route| {
+        let route = route.trim_start();
+        let path = route.strip_prefix('"')?.split('"').next()?;
+        let method = route.split("web::").nth(1)?.split('(').next()?;
+        Some((format!("{}{}", prefix, path), method.to_string()))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn spec_covers_all_routes() {
+    let mut all = routes(include_str!("server.rs"), ".route(", "/api");
+    all.extend(routes(include_str!("server.rs"), "web::resource(", ""));
+    all.extend(routes(include_str!("api_v1.rs"), ".route(", "/api/v1"));
+    assert!(all.len() > 20);
+    let spec = ApiDoc::openapi();
+    for (path, method) in all {
+      let item = spec
+        .paths
+        .paths
+        .get(&path)
+        .unwrap_or_else(|| panic!("Route {} is missing from the OpenAPI spec", path));
+      let operation = match method.as_str() {
+        "get" => &item.get,
+        "post" => &item.post,
+        "put" => &item.put,
+        "patch" => &item.patch,
+        "delete" => &item.delete,
+        _ => panic!("Unknown method {} of route {}", method, path),
+      };
+      assert!(
+        operation.is_some(),
+        "Route {} {} is missing from the OpenAPI spec",
+        method,
+        path
+      );
+    }
+  }
+}
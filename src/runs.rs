@@ -0,0 +1,263 @@
+use crate::events;
+use crate::types::*;
+use crate::utils::*;
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use futures_util::stream;
+use log::error;
+use std::io::SeekFrom;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::broadcast::{error::TryRecvError, Receiver};
+use tokio::time::{sleep, Duration};
+
+/// How often a followed log is checked for new output.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where the output of run `id` ends, relative to where it starts, `None`
+/// while the run is going.
+async fn finished_output_end(state: &RS, id: u64) -> Option<u64> {
+  state
+    .read(move |rtodo| match rtodo.find_run(id) {
+      Some(run) if run.finished_at.is_none() => None,
+      Some(run) => Some(
+        run
+          .output_end
+          .map_or(u64::MAX, |end| end.saturating_sub(run.output_start)),
+      ),
+      None => Some(u64::MAX),
+    })
+    .await
+    .unwrap_or(Some(u64::MAX))
+}
+
+/// Whether `events` since the last call may have ended run `id`, so that
+/// followers only ask the core again then.
+fn may_have_ended(events: &mut Receiver<Event>, id: u64) -> bool {
+  let mut ended = false;
+  loop {
+    match events.try_recv() {
+      Ok(event) => {
+        ended |= match event.kind {
+          EventKind::RunFinished { run_id, .. } => run_id == id,
+          EventKind::RunFailed { run_id, .. } => run_id == Some(id),
+          // Removes works along with their runs.
+          EventKind::EntryDeleted | EventKind::ConfigReloaded => true,
+          _ => false,
+        }
+      }
+      Err(TryRecvError::Empty) => return ended,
+      Err(TryRecvError::Lagged(_)) => ended = true,
+      Err(TryRecvError::Closed) => return true,
+    }
+  }
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/runs",
+  params(EntryFilter),
+  responses(
+    (status = 200, description = "Running and recently finished runs, oldest first", body = Vec<RunInfo>),
+    (status = 401, description = "Missing or invalid token", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+pub async fn get_runs(
+  req: HttpRequest,
+  filter: web::Query<EntryFilter>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let token = api_token(&req, None);
+  let filter = filter.into_inner();
+  let runs = state
+    .read(move |rtodo| {
+      let grant = check_request_token(token.as_deref(), rtodo, TokenScope::Read)?;
+      Ok::<Vec<RunInfo>, ApiError>(
+        rtodo
+          .get_runs()
+          .into_iter()
+          .filter(|run| filter.matches_entry(run.entry_id, &run.entry_name))
+          .filter(|run| rtodo.covers(&grant, run.entry_id))
+          .collect(),
+      )
+    })
+    .await??;
+  Ok(HttpResponse::Ok().json(runs))
+}
+
+#[utoipa::path(
+  get,
+  path = "/api/runs/{id}/log",
+  params(("id" = u64, Path, description = "Run id"), LogQuery),
+  responses(
+    (status = 200, description = "Output of the run, in follow mode streamed until the run finishes", body = String, content_type = "text/plain"),
+    (status = 206, description = "The requested byte range of the output", body = String, content_type = "text/plain"),
+    (status = 401, description = "Missing or invalid token", body = ApiError),
+    (status = 403, description = "The token isn't allowed on the run's entry", body = ApiError),
+    (status = 404, description = "Run not found or its output is not captured", body = ApiError),
+    (status = 416, description = "Range starts past the end of the output", body = ApiError)
+  ),
+  security(("bearer" = []))
+)]
+pub async fn run_log(
+  req: HttpRequest,
+  path: web::Path<u64>,
+  query: web::Query<LogQuery>,
+  state: RS,
+) -> Result<HttpResponse, ApiError> {
+  let id = path.into_inner();
+  let token = api_token(&req, None);
+  let (output, output_start, output_end) = state
+    .read(move |rtodo| {
+      let grant = check_request_token(token.as_deref(), rtodo, TokenScope::Read)?;
+      let run = rtodo
+        .find_run(id)
+        .ok_or_else(|| ApiError::new(ApiErrorKind::NotFound, format!("Run {} not found", id)))?;
+      if !rtodo.covers(&grant, run.entry_id) {
+        return Err(ApiError::forbidden(format!(
+          "Token {} is not allowed on entry {}",
+          grant.name, run.entry_name
+        )));
+      }
+      let output = run.output_file.ok_or_else(|| {
+        ApiError::new(
+          ApiErrorKind::NotFound,
+          format!("Output of run {} is not captured", id),
+        )
+      })?;
+      Ok((output, run.output_start, run.output_end))
+    })
+    .await??;
+  let mut file = File::open(&output).await.map_err(|err| {
+    ApiError::new(
+      ApiErrorKind::NotFound,
+      format!("Output of run {} is unavailable, {}", id, err),
+    )
+  })?;
+  let file_size = file
+    .metadata()
+    .await
+    .map_err(|err| ApiError::internal(err.into()))?
+    .len();
+  // Other runs of the entry may have written to the same file, offsets are
+  // within the run's own part of it.
+  let base = output_start.min(file_size);
+  let size = output_end
+    .unwrap_or(file_size)
+    .min(file_size)
+    .saturating_sub(base);
+  let range_header = req
+    .headers()
+    .get(header::RANGE)
+    .and_then(|value| value.to_str().ok());
+  let range = range_header.and_then(|value| parse_byte_range(value, size));
+  let (start, end) = match range {
+    Some(range) => range,
+    None => {
+      let start = query.offset.unwrap_or(0);
+      let end = match query.length {
+        Some(length) => size.min(start.saturating_add(length)),
+        None => size,
+      };
+      (start, end)
+    }
+  };
+  if start > size || (range.is_some() && start >= size) {
+    return Err(ApiError::new(
+      ApiErrorKind::OutOfRange,
+      format!(
+        "Offset {} is past the end of the output, {} bytes",
+        start, size
+      ),
+    ));
+  }
+  file
+    .seek(SeekFrom::Start(base + start))
+    .await
+    .map_err(|err| ApiError::internal(err.into()))?;
+  if query.follow {
+    // Where the requested range ends once the output gets there, output to
+    // come counts as well.
+    let bound = match (range, query.length) {
+      (Some(_), _) => range_header
+        .and_then(|value| parse_byte_range(value, u64::MAX))
+        .map(|(_, end)| end)
+        .filter(|end| *end != u64::MAX),
+      (None, Some(length)) => Some(start.saturating_add(length)),
+      (None, None) => None,
+    };
+    // Subscribed before the first look, so that the run can't end unnoticed
+    // in between.
+    let events = events::listen();
+    let finished = finished_output_end(&state, id).await;
+    let stream = stream::unfold(
+      (file, state, events, finished, start),
+      move |(mut file, state, mut events, mut finished, mut pos)| async move {
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+          // Checked before reading so that output written right before the
+          // run exited is still sent.
+          if finished.is_none() && may_have_ended(&mut events, id) {
+            finished = finished_output_end(&state, id).await;
+          }
+          let limit = match (bound, finished) {
+            (Some(bound), Some(finished)) => Some(bound.min(finished)),
+            (bound, finished) => bound.or(finished),
+          };
+          let want = limit.map_or(CHUNK_SIZE as u64, |limit| {
+            limit.saturating_sub(pos).min(CHUNK_SIZE as u64)
+          });
+          if want == 0 {
+            return None;
+          }
+          match file.read(&mut buf[..want as usize]).await {
+            Ok(0) if finished.is_some() => return None,
+            Ok(0) => sleep(FOLLOW_INTERVAL).await,
+            Ok(len) => {
+              pos += len as u64;
+              buf.truncate(len);
+              return Some((
+                Ok::<_, actix_web::Error>(web::Bytes::from(buf)),
+                (file, state, events, finished, pos),
+              ));
+            }
+            Err(err) => {
+              error!("Error: Failed to read output of run {}, Err: {}", id, err);
+              return None;
+            }
+          }
+        }
+      },
+    );
+    return Ok(
+      HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .streaming(stream),
+    );
+  }
+  let mut buf = Vec::new();
+  file
+    .take(end.saturating_sub(start))
+    .read_to_end(&mut buf)
+    .await
+    .map_err(|err| ApiError::internal(err.into()))?;
+  let mut res = match range {
+    Some(_) => {
+      let mut res = HttpResponse::PartialContent();
+      res.insert_header((
+        header::CONTENT_RANGE,
+        format!("bytes {}-{}/{}", start, end.saturating_sub(1), size),
+      ));
+      res
+    }
+    None => HttpResponse::Ok(),
+  };
+  Ok(
+    res
+      .insert_header((header::ACCEPT_RANGES, "bytes"))
+      .content_type("text/plain; charset=utf-8")
+      .body(buf),
+  )
+}
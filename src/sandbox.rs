@@ -0,0 +1,478 @@
+use crate::types::{Sandbox, Seccomp};
+
+use nix::errno::Errno;
+use nix::libc::{self, c_long, c_ulong, sock_filter, sock_fprog};
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::ptr;
+
+/// Denied by `Seccomp::System`.
+const SYSTEM_CALLS: &[(&str, c_long)] = &[
+  ("mount", libc::SYS_mount),
+  ("umount2", libc::SYS_umount2),
+  ("pivot_root", libc::SYS_pivot_root),
+  ("fsopen", libc::SYS_fsopen),
+  ("fsconfig", libc::SYS_fsconfig),
+  ("fsmount", libc::SYS_fsmount),
+  ("fspick", libc::SYS_fspick),
+  ("move_mount", libc::SYS_move_mount),
+  ("open_tree", libc::SYS_open_tree),
+  ("swapon", libc::SYS_swapon),
+  ("swapoff", libc::SYS_swapoff),
+  ("reboot", libc::SYS_reboot),
+  ("init_module", libc::SYS_init_module),
+  ("finit_module", libc::SYS_finit_module),
+  ("delete_module", libc::SYS_delete_module),
+  ("kexec_load", libc::SYS_kexec_load),
+  ("kexec_file_load", libc::SYS_kexec_file_load),
+  ("settimeofday", libc::SYS_settimeofday),
+  ("clock_settime", libc::SYS_clock_settime),
+  ("clock_adjtime", libc::SYS_clock_adjtime),
+  ("adjtimex", libc::SYS_adjtimex),
+  ("ptrace", libc::SYS_ptrace),
+  ("process_vm_readv", libc::SYS_process_vm_readv),
+  ("process_vm_writev", libc::SYS_process_vm_writev),
+  ("kcmp", libc::SYS_kcmp),
+  ("bpf", libc::SYS_bpf),
+  ("perf_event_open", libc::SYS_perf_event_open),
+  ("userfaultfd", libc::SYS_userfaultfd),
+  ("acct", libc::SYS_acct),
+  ("quotactl", libc::SYS_quotactl),
+  ("setns", libc::SYS_setns),
+  ("unshare", libc::SYS_unshare),
+  ("keyctl", libc::SYS_keyctl),
+  ("add_key", libc::SYS_add_key),
+  ("request_key", libc::SYS_request_key),
+  ("open_by_handle_at", libc::SYS_open_by_handle_at),
+  ("name_to_handle_at", libc::SYS_name_to_handle_at),
+  ("sethostname", libc::SYS_sethostname),
+  ("setdomainname", libc::SYS_setdomainname),
+  ("syslog", libc::SYS_syslog),
+];
+
+/// The others `Seccomp::Deny` knows by name.
+const OTHER_CALLS: &[(&str, c_long)] = &[
+  ("socket", libc::SYS_socket),
+  ("socketpair", libc::SYS_socketpair),
+  ("connect", libc::SYS_connect),
+  ("bind", libc::SYS_bind),
+  ("listen", libc::SYS_listen),
+  ("accept", libc::SYS_accept),
+  ("accept4", libc::SYS_accept4),
+  ("execve", libc::SYS_execve),
+  ("execveat", libc::SYS_execveat),
+  ("clone", libc::SYS_clone),
+  ("clone3", libc::SYS_clone3),
+  ("kill", libc::SYS_kill),
+  ("chroot", libc::SYS_chroot),
+  ("setuid", libc::SYS_setuid),
+  ("setgid", libc::SYS_setgid),
+  ("setreuid", libc::SYS_setreuid),
+  ("setregid", libc::SYS_setregid),
+  ("setresuid", libc::SYS_setresuid),
+  ("setresgid", libc::SYS_setresgid),
+  ("setgroups", libc::SYS_setgroups),
+  ("capset", libc::SYS_capset),
+  ("prctl", libc::SYS_prctl),
+  ("ioctl", libc::SYS_ioctl),
+];
+
+/// Capabilities by number, see capabilities(7).
+const CAPABILITIES: &[&str] = &[
+  "CAP_CHOWN",
+  "CAP_DAC_OVERRIDE",
+  "CAP_DAC_READ_SEARCH",
+  "CAP_FOWNER",
+  "CAP_FSETID",
+  "CAP_KILL",
+  "CAP_SETGID",
+  "CAP_SETUID",
+  "CAP_SETPCAP",
+  "CAP_LINUX_IMMUTABLE",
+  "CAP_NET_BIND_SERVICE",
+  "CAP_NET_BROADCAST",
+  "CAP_NET_ADMIN",
+  "CAP_NET_RAW",
+  "CAP_IPC_LOCK",
+  "CAP_IPC_OWNER",
+  "CAP_SYS_MODULE",
+  "CAP_SYS_RAWIO",
+  "CAP_SYS_CHROOT",
+  "CAP_SYS_PTRACE",
+  "CAP_SYS_PACCT",
+  "CAP_SYS_ADMIN",
+  "CAP_SYS_BOOT",
+  "CAP_SYS_NICE",
+  "CAP_SYS_RESOURCE",
+  "CAP_SYS_TIME",
+  "CAP_SYS_TTY_CONFIG",
+  "CAP_MKNOD",
+  "CAP_LEASE",
+  "CAP_AUDIT_WRITE",
+  "CAP_AUDIT_CONTROL",
+  "CAP_SETFCAP",
+  "CAP_MAC_OVERRIDE",
+  "CAP_MAC_ADMIN",
+  "CAP_SYSLOG",
+  "CAP_WAKE_ALARM",
+  "CAP_BLOCK_SUSPEND",
+  "CAP_AUDIT_READ",
+  "CAP_PERFMON",
+  "CAP_BPF",
+  "CAP_CHECKPOINT_RESTORE",
+];
+
+/// What seccomp filters see of the running architecture, see audit.h.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Set in the numbers of x32 system calls, which would get around the filter.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: Option<u32> = Some(0x4000_0000);
+#[cfg(not(target_arch = "x86_64"))]
+const X32_SYSCALL_BIT: Option<u32> = None;
+
+/// `_LINUX_CAPABILITY_VERSION_3`, with 64 bit sets.
+const CAPABILITY_VERSION: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+  version: u32,
+  pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+  effective: u32,
+  permitted: u32,
+  inheritable: u32,
+}
+
+/// A sandbox ready to be entered between fork and exec, everything that
+/// allocates is done beforehand.
+pub struct Prepared {
+  namespaces: libc::c_int,
+  read_only_paths: Vec<CString>,
+  tmp_dirs: Vec<CString>,
+  working_dir: CString,
+  /// The capabilities to keep as a mask, and the last the kernel knows.
+  capabilities: Option<(u64, u32)>,
+  no_new_privileges: bool,
+  filter: Option<Vec<sock_filter>>,
+}
+
+fn c_string(value: &str) -> io::Result<CString> {
+  CString::new(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Capabilities by name, with or without `CAP_`, as a mask.
+pub fn capability_mask(names: &[String]) -> Result<u64, Box<dyn Error>> {
+  let mut mask = 0;
+  for name in names {
+    let upper = name.to_uppercase();
+    let full = match upper.starts_with("CAP_") {
+      true => upper,
+      false => format!("CAP_{}", upper),
+    };
+    match CAPABILITIES.iter().position(|cap| *cap == full) {
+      Some(index) => mask |= 1 << index,
+      None => return Err(format!("Unknown capability {}", name).into()),
+    }
+  }
+  Ok(mask)
+}
+
+/// The number of a system call denied by name, on this architecture.
+pub fn system_call(name: &str) -> Result<c_long, Box<dyn Error>> {
+  SYSTEM_CALLS
+    .iter()
+    .chain(OTHER_CALLS)
+    .find(|(known, _)| *known == name)
+    .map(|(_, number)| *number)
+    .ok_or_else(|| format!("Unknown system call {}", name).into())
+}
+
+fn statement(code: u32, k: u32) -> sock_filter {
+  sock_filter {
+    code: code as u16,
+    jt: 0,
+    jf: 0,
+    k,
+  }
+}
+
+/// Skips the next instruction unless the accumulator compares to `k`.
+fn jump(code: u32, k: u32) -> sock_filter {
+  sock_filter {
+    jf: 1,
+    ..statement(libc::BPF_JMP | code | libc::BPF_K, k)
+  }
+}
+
+/// A filter failing `denied` with EPERM and killing the process on a foreign
+/// architecture, as a BPF program over `struct seccomp_data`.
+fn filter(denied: &[c_long]) -> Result<Vec<sock_filter>, Box<dyn Error>> {
+  let arch = AUDIT_ARCH.ok_or("Seccomp isn't supported on this architecture")?;
+  let load = |offset| statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+  let ret = |action| statement(libc::BPF_RET | libc::BPF_K, action);
+  let deny = ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+  let mut program = vec![
+    load(4),
+    sock_filter {
+      jt: 1,
+      jf: 0,
+      ..jump(libc::BPF_JEQ, arch)
+    },
+    ret(libc::SECCOMP_RET_KILL_PROCESS),
+    load(0),
+  ];
+  if let Some(bit) = X32_SYSCALL_BIT {
+    program.extend([jump(libc::BPF_JGE, bit), deny]);
+  }
+  for number in denied {
+    program.extend([jump(libc::BPF_JEQ, *number as u32), deny]);
+  }
+  program.push(ret(libc::SECCOMP_RET_ALLOW));
+  Ok(program)
+}
+
+/// Checks `sandbox` and prepares entering it, `working_dir` is where the run
+/// starts.
+pub fn prepare(sandbox: &Sandbox, working_dir: &str) -> Result<Prepared, Box<dyn Error>> {
+  let mut namespaces = 0;
+  if sandbox.private_tmp || !sandbox.read_only_paths.is_empty() {
+    namespaces |= libc::CLONE_NEWNS;
+  }
+  if sandbox.private_network {
+    namespaces |= libc::CLONE_NEWNET;
+  }
+  let mut read_only_paths = Vec::new();
+  for path in &sandbox.read_only_paths {
+    if !Path::new(path).exists() {
+      return Err(format!("Read-only path {} doesn't exist", path).into());
+    }
+    read_only_paths.push(c_string(path)?);
+  }
+  let tmp_dirs = match sandbox.private_tmp {
+    true => ["/tmp", "/var/tmp"]
+      .into_iter()
+      .filter(|dir| Path::new(dir).is_dir())
+      .map(c_string)
+      .collect::<io::Result<_>>()?,
+    false => Vec::new(),
+  };
+  let capabilities = match &sandbox.capabilities {
+    Some(names) => {
+      let last = fs::read_to_string("/proc/sys/kernel/cap_last_cap")?
+        .trim()
+        .parse()?;
+      Some((capability_mask(names)?, last))
+    }
+    None => None,
+  };
+  let filter = match &sandbox.seccomp {
+    Some(Seccomp::System) => Some(filter(
+      &SYSTEM_CALLS
+        .iter()
+        .map(|(_, number)| *number)
+        .collect::<Vec<_>>(),
+    )?),
+    Some(Seccomp::Deny(names)) => Some(filter(
+      &names
+        .iter()
+        .map(|name| system_call(name))
+        .collect::<Result<Vec<_>, _>>()?,
+    )?),
+    None => None,
+  };
+  Ok(Prepared {
+    namespaces,
+    read_only_paths,
+    tmp_dirs,
+    working_dir: c_string(working_dir)?,
+    capabilities,
+    // Unprivileged processes may only install filters this way.
+    no_new_privileges: sandbox.no_new_privileges || filter.is_some(),
+    filter,
+  })
+}
+
+fn mount(
+  source: Option<&CStr>,
+  target: &CStr,
+  fstype: Option<&CStr>,
+  flags: c_ulong,
+  data: Option<&CStr>,
+) -> io::Result<()> {
+  let ptr = |value: Option<&CStr>| value.map_or(ptr::null(), CStr::as_ptr);
+  Errno::result(unsafe {
+    libc::mount(
+      ptr(source),
+      target.as_ptr(),
+      ptr(fstype),
+      flags,
+      ptr(data).cast(),
+    )
+  })?;
+  Ok(())
+}
+
+impl Prepared {
+  /// Moves the calling process into the sandbox. Runs between fork and exec,
+  /// so it sticks to async-signal-safe calls. Each step needs privileges the
+  /// next ones give up.
+  pub fn enter(&self) -> io::Result<()> {
+    if self.namespaces != 0 {
+      Errno::result(unsafe { libc::unshare(self.namespaces) })?;
+    }
+    if self.namespaces & libc::CLONE_NEWNS != 0 {
+      // Keeps the mounts below from propagating back to the host.
+      mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+      for path in &self.read_only_paths {
+        mount(Some(path), path, None, libc::MS_BIND | libc::MS_REC, None)?;
+        mount(
+          None,
+          path,
+          None,
+          libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+          None,
+        )?;
+      }
+      for dir in &self.tmp_dirs {
+        mount(
+          Some(c"tmpfs"),
+          dir,
+          Some(c"tmpfs"),
+          libc::MS_NOSUID | libc::MS_NODEV,
+          Some(c"mode=1777"),
+        )?;
+      }
+      // The working directory was entered before, it may be mounted over.
+      Errno::result(unsafe { libc::chdir(self.working_dir.as_ptr()) })?;
+    }
+    if let Some((keep, last)) = self.capabilities {
+      for cap in (0..=last).filter(|cap| keep & 1 << cap == 0) {
+        Errno::result(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as c_ulong, 0, 0, 0) })?;
+      }
+      Errno::result(unsafe {
+        libc::prctl(
+          libc::PR_CAP_AMBIENT,
+          libc::PR_CAP_AMBIENT_CLEAR_ALL as c_ulong,
+          0,
+          0,
+          0,
+        )
+      })?;
+      let header = CapHeader {
+        version: CAPABILITY_VERSION,
+        pid: 0,
+      };
+      let mut data = [CapData::default(); 2];
+      Errno::result(unsafe { libc::syscall(libc::SYS_capget, &header, data.as_mut_ptr()) })?;
+      for (index, set) in data.iter_mut().enumerate() {
+        let mask = (keep >> (32 * index)) as u32;
+        set.effective &= mask;
+        set.permitted &= mask;
+        set.inheritable &= mask;
+      }
+      Errno::result(unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) })?;
+    }
+    if self.no_new_privileges {
+      Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as c_ulong, 0, 0, 0) })?;
+    }
+    if let Some(filter) = &self.filter {
+      let program = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut sock_filter,
+      };
+      Errno::result(unsafe {
+        libc::prctl(
+          libc::PR_SET_SECCOMP,
+          libc::SECCOMP_MODE_FILTER as c_ulong,
+          &program as *const sock_fprog,
+          0,
+          0,
+        )
+      })?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fields(program: &[sock_filter]) -> Vec<(u16, u8, u8, u32)> {
+    program
+      .iter()
+      .map(|op| (op.code, op.jt, op.jf, op.k))
+      .collect()
+  }
+
+  #[test]
+  fn filter_denies_each_call_then_allows() {
+    let Some(arch) = AUDIT_ARCH else { return };
+    let denied = [libc::SYS_mount, libc::SYS_ptrace];
+    let load = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    let jump = |code| (libc::BPF_JMP | code | libc::BPF_K) as u16;
+    let ret = (libc::BPF_RET | libc::BPF_K) as u16;
+    let deny = (ret, 0, 0, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    // The architecture, jumping over the kill if it matches, then the number.
+    let mut expected = vec![
+      (load, 0, 0, 4),
+      (jump(libc::BPF_JEQ), 1, 0, arch),
+      (ret, 0, 0, libc::SECCOMP_RET_KILL_PROCESS),
+      (load, 0, 0, 0),
+    ];
+    if let Some(bit) = X32_SYSCALL_BIT {
+      expected.extend([(jump(libc::BPF_JGE), 0, 1, bit), deny]);
+    }
+    for number in denied {
+      expected.extend([(jump(libc::BPF_JEQ), 0, 1, number as u32), deny]);
+    }
+    expected.push((ret, 0, 0, libc::SECCOMP_RET_ALLOW));
+    assert_eq!(fields(&filter(&denied).unwrap()), expected);
+  }
+
+  #[test]
+  fn system_calls_by_name() {
+    assert_eq!(system_call("mount").unwrap(), libc::SYS_mount);
+    assert_eq!(system_call("socket").unwrap(), libc::SYS_socket);
+    assert!(system_call("sockett").is_err());
+    assert!(system_call("").is_err());
+  }
+
+  #[test]
+  fn capability_mask_bits() {
+    let names = |names: &[&str]| {
+      names
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(capability_mask(&[]).unwrap(), 0);
+    assert_eq!(capability_mask(&names(&["CAP_CHOWN"])).unwrap(), 1);
+    assert_eq!(
+      capability_mask(&names(&["net_bind_service"])).unwrap(),
+      1 << 10
+    );
+    assert_eq!(
+      capability_mask(&names(&["SYS_ADMIN", "cap_kill"])).unwrap(),
+      1 << 21 | 1 << 5
+    );
+    assert_eq!(
+      capability_mask(&names(&["CAP_CHECKPOINT_RESTORE"])).unwrap(),
+      1 << 40
+    );
+    assert!(capability_mask(&names(&["CAP_CHOWN", "NET_BIND"])).is_err());
+  }
+}
@@ -0,0 +1,283 @@
+use crate::cgroup;
+use crate::types::*;
+
+use log::error;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{pipe2, read, write};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::iter;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time;
+
+/// Longest the loops sleep, so that they keep beating while idle.
+pub const IDLE_WAKEUP: time::Duration = time::Duration::from_secs(1);
+
+static CHILD_EXITS: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_child_exit(_: i32) {
+  // Only async-signal-safe calls here. A full pipe already wakes the reaper.
+  let _ = write(CHILD_EXITS.load(Ordering::Relaxed), &[0]);
+}
+
+/// Installs a SIGCHLD handler writing to a pipe, which `wait_for_child_exit` polls.
+pub fn watch_child_exits() -> Result<RawFd, nix::Error> {
+  let (read_end, write_end) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+  CHILD_EXITS.store(write_end, Ordering::Relaxed);
+  let action = SigAction::new(
+    SigHandler::Handler(on_child_exit),
+    SaFlags::SA_RESTART | SaFlags::SA_NOCLDSTOP,
+    SigSet::empty(),
+  );
+  unsafe { sigaction(Signal::SIGCHLD, &action) }?;
+  Ok(read_end)
+}
+
+/// Whether a child exited, or a run's cgroup changed, see
+/// `cgroup::events_fd`, within `IDLE_WAKEUP`.
+pub fn wait_for_child_exit(exits: RawFd, cgroups: Option<RawFd>) -> bool {
+  let mut fds: Vec<PollFd> = iter::once(exits)
+    .chain(cgroups)
+    .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
+    .collect();
+  match poll(&mut fds, IDLE_WAKEUP.as_millis() as i32) {
+    Ok(0) => false,
+    Ok(_) => {
+      let mut buf = [0; 64];
+      while matches!(read(exits, &mut buf), Ok(len) if len > 0) {}
+      cgroup::drain_events();
+      true
+    }
+    Err(nix::Error::EINTR) => false,
+    Err(err) => {
+      error!("Error: Failed to wait for child exits, Err: {}", err);
+      std::thread::sleep(IDLE_WAKEUP);
+      true
+    }
+  }
+}
+
+/// When a work next needs the core, as a unix timestamp. Paused works are
+/// due when their pause ends, works in error never.
+pub fn due_time(work: &Work) -> Option<i64> {
+  match (&work.status, &work.entry.trigger) {
+    (Status::Paused, _) => work
+      .entry
+      .paused_until
+      .as_ref()
+      .map(|until| until.timestamp),
+    (Status::Error, _) | (_, Trigger::None) => None,
+    (_, Trigger::Timer(_)) => work
+      .trigger_state
+      .effective_exec_time
+      .as_ref()
+      .map(|time| time.timestamp),
+  }
+}
+
+/// Works ordered by their next due time.
+#[derive(Default)]
+pub struct Schedule {
+  due: BinaryHeap<Reverse<(i64, u32)>>,
+  /// Position of each entry's work in `Rtodo::works` at the last rebuild.
+  index: HashMap<u32, usize>,
+}
+
+impl Schedule {
+  /// Reads the due times of all works again, the positions of the works are
+  /// only valid until `Rtodo::works` changes.
+  pub fn rebuild(&mut self, works: &[Work]) {
+    self.due.clear();
+    self.index.clear();
+    for (index, work) in works.iter().enumerate() {
+      self.index.insert(work.entry.id, index);
+      self.push(work);
+    }
+  }
+
+  pub fn push(&mut self, work: &Work) {
+    if let Some(due) = due_time(work) {
+      self.due.push(Reverse((due, work.entry.id)));
+    }
+  }
+
+  /// Time left until the earliest due time, from `now_millis`.
+  pub fn next_due(&self, now_millis: i64) -> Option<time::Duration> {
+    self.due.peek().map(|Reverse((due, _))| {
+      time::Duration::from_millis((due * 1000).saturating_sub(now_millis).max(0) as u64)
+    })
+  }
+
+  /// Takes the next work that is due at `now`, with its due time and position.
+  pub fn pop_due(&mut self, now: i64) -> Option<(i64, usize)> {
+    while let Some(Reverse((due, id))) = self.due.peek().copied() {
+      if due > now {
+        return None;
+      }
+      self.due.pop();
+      if let Some(index) = self.index.get(&id) {
+        return Some((due, *index));
+      }
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Instant;
+
+  fn works(count: u32) -> Vec<Work> {
+    (1..=count)
+      .map(|id| {
+        let mut entry = Entry::new(
+          Trigger::Timer(Timer::Repeat(Duration::from_sec(60 + id % 3600))),
+          Logger::default(),
+          Action::None,
+          DoIfRunning::Continue,
+          Status::Pending,
+        );
+        entry.id = id;
+        entry.name = format!("bench-{}", id);
+        Work::new(entry)
+      })
+      .collect()
+  }
+
+  /// Works due at `times`, in that order.
+  fn due_at(times: &[i64]) -> Vec<Work> {
+    let mut works = works(times.len() as u32);
+    for (work, timestamp) in works.iter_mut().zip(times) {
+      work.trigger_state.effective_exec_time = Some(DateTime {
+        timestamp: *timestamp,
+        ..Default::default()
+      });
+    }
+    works
+  }
+
+  fn drain(schedule: &mut Schedule, now: i64) -> Vec<(i64, usize)> {
+    std::iter::from_fn(|| schedule.pop_due(now)).collect()
+  }
+
+  #[test]
+  fn pops_due_works_in_order() {
+    let mut schedule = Schedule::default();
+    schedule.rebuild(&due_at(&[30, 10, 20]));
+    assert_eq!(schedule.next_due(5000), Some(time::Duration::from_secs(5)));
+    assert_eq!(drain(&mut schedule, 15), [(10, 1)]);
+    assert_eq!(drain(&mut schedule, 100), [(20, 2), (30, 0)]);
+    assert_eq!(schedule.next_due(0), None);
+  }
+
+  #[test]
+  fn rescheduled_works_keep_the_order() {
+    let mut works = due_at(&[30, 10, 20]);
+    let mut schedule = Schedule::default();
+    schedule.rebuild(&works);
+    assert_eq!(drain(&mut schedule, 10), [(10, 1)]);
+    works[1]
+      .trigger_state
+      .effective_exec_time
+      .as_mut()
+      .unwrap()
+      .timestamp = 25;
+    schedule.push(&works[1]);
+    works[0]
+      .trigger_state
+      .effective_exec_time
+      .as_mut()
+      .unwrap()
+      .timestamp = 5;
+    schedule.push(&works[0]);
+    // The earlier time of work 0 comes first, its old one stays queued and
+    // the core skips it by comparing with `due_time`.
+    assert_eq!(
+      drain(&mut schedule, 100),
+      [(5, 0), (20, 2), (25, 1), (30, 0)]
+    );
+  }
+
+  #[test]
+  fn removed_works_are_not_due() {
+    let mut works = due_at(&[30, 10, 20]);
+    let mut schedule = Schedule::default();
+    schedule.rebuild(&works);
+    works.remove(1);
+    schedule.rebuild(&works);
+    assert_eq!(drain(&mut schedule, 100), [(20, 1), (30, 0)]);
+    works[0].status = Status::Error;
+    schedule.rebuild(&works);
+    assert_eq!(drain(&mut schedule, 100), [(20, 1)]);
+  }
+
+  /// What the old executor did every 100 ms, for comparison. It cloned every
+  /// work to get out of its lock.
+  fn poll_all(works: &[Work]) -> usize {
+    works
+      .iter()
+      .map(|work| std::hint::black_box(work.clone()))
+      .filter(|work| work.pause_expired() || work.trigger_state.is_up())
+      .count()
+  }
+
+  /// Bounds loose enough for debug builds on a busy machine, what matters is
+  /// that an idle wakeup no longer goes over every work.
+  #[test]
+  fn bench_10k_entries() {
+    let works = works(10_000);
+    let now = chrono::Local::now().timestamp();
+
+    let start = Instant::now();
+    let mut schedule = Schedule::default();
+    schedule.rebuild(&works);
+    let rebuild = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..1000 {
+      assert!(schedule.pop_due(now).is_none());
+      assert!(schedule.next_due(now * 1000).is_some());
+    }
+    let idle = start.elapsed() / 1000;
+
+    let start = Instant::now();
+    for _ in 0..10 {
+      assert_eq!(poll_all(&works), 0);
+    }
+    let polled = start.elapsed() / 10;
+
+    let start = Instant::now();
+    let mut due = 0;
+    while schedule.pop_due(i64::MAX).is_some() {
+      due += 1;
+    }
+    let drain = start.elapsed();
+    assert_eq!(due, works.len());
+
+    assert!(
+      rebuild < time::Duration::from_millis(500),
+      "rebuild {:?}",
+      rebuild
+    );
+    assert!(
+      drain < time::Duration::from_millis(500),
+      "drain {:?}",
+      drain
+    );
+    assert!(
+      idle < time::Duration::from_millis(1),
+      "idle wakeup {:?}",
+      idle
+    );
+    assert!(
+      idle * 10 < polled,
+      "idle wakeup {:?}, polling pass {:?}",
+      idle,
+      polled
+    );
+  }
+}
@@ -1,174 +1,784 @@
-use std::sync::{Arc, RwLock};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream as StdUnixStream};
+use std::path::Path;
+use std::sync::mpsc::Sender;
 
+use crate::api_v1;
+use crate::audit::{self, EntryAudit};
+use crate::events;
+use crate::health;
+use crate::metrics;
+use crate::openapi;
+use crate::runs;
+use crate::systemd::{self, Listener};
+use crate::tls;
 use crate::types::*;
 use crate::utils::*;
 
 use actix_cors::Cors;
-use actix_web::{middleware::Logger, web, App, HttpServer, Responder};
+use actix_web::dev::Service;
+use actix_web::rt::net::UnixStream;
+use actix_web::{middleware::Logger, web, App, HttpRequest, HttpServer, Responder, ResponseError};
 use log::{error, info};
+use nix::sys::socket::{bind, listen, socket, AddressFamily, SockFlag, SockType, UnixAddr};
+use nix::sys::stat::{fchmod, Mode};
 use tokio::runtime::Runtime;
 
+#[utoipa::path(get, path = "/", responses((status = 200, body = ResCommonData<String>)))]
 async fn hello() -> impl Responder {
   nsucc(200, "Hello world")
 }
 
-async fn validate_token(data: ReqData, state: RS) -> impl Responder {
-  let rtodo = get_rtodo_read_gurad(&state).await;
-  if !check_token(&data, &rtodo) {
-    return nerr(100, "Invalid token");
-  }
-  nsucc(200, "Valid token")
+#[utoipa::path(get, path = "/api/", responses((status = 200, body = String)))]
+async fn api_index() -> impl Responder {
+  String::from("Hello, rtodo!")
 }
 
-async fn get_entries(data: ReqData, state: RS) -> impl Responder {
-  let rtodo = get_rtodo_read_gurad(&state).await;
-  if !check_token(&data, &rtodo) {
-    return nerr(100, "Invalid token");
-  }
-  nsucc(200, rtodo.get_entries())
+#[utoipa::path(
+  post,
+  path = "/api/validateToken",
+  request_body = ReqToken,
+  responses((status = 200, description = "Code 200 if the token is valid, 100 otherwise", body = ResCommonData<String>))
+)]
+async fn validate_token(data: ReqData, state: RS) -> Result<String, ApiError> {
+  state
+    .read(move |rtodo| {
+      if let Err(e) = check_token(&data, rtodo, TokenScope::Read) {
+        return nerr(100, &e);
+      }
+      nsucc(200, "Valid token")
+    })
+    .await
 }
 
-async fn add_entries(data: ReqDataT<Vec<Entry>>, state: RS) -> impl Responder {
-  let mut rtodo = get_rtodo_write_gurad(&state).await;
-  if !data.check_token(&rtodo) {
-    return nerr(100, "Invalid token");
-  }
-  for entry in match &data.data {
-    Some(d) => d.clone(),
-    None => {
-      return nerr(100, "Invalid data");
-    }
-  } {
-    match rtodo.add_entry(entry) {
-      Ok(_) => {}
-      Err(e) => {
-        return nerr(100, &format!("Failed to add entry: {}", e));
+#[utoipa::path(
+  post,
+  path = "/api/getEntries",
+  request_body = ReqToken,
+  responses((status = 200, description = "All configured entries", body = ResCommonData<Vec<Entry>>))
+)]
+async fn get_entries(data: ReqData, state: RS) -> Result<String, ApiError> {
+  state
+    .read(move |rtodo| {
+      let grant = match check_token(&data, rtodo, TokenScope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return nerr(100, &e),
+      };
+      let entries: Vec<Entry> = rtodo
+        .get_entries()
+        .into_iter()
+        .filter(|entry| grant.covers_entry(entry))
+        .collect();
+      nsucc(200, entries)
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/addEntries",
+  request_body = ReqCommonData<Vec<Entry>>,
+  responses((status = 200, description = "Code 200 on success, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn add_entries(
+  req: HttpRequest,
+  data: ReqDataT<Vec<Entry>>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      let entries = match &data.data {
+        Some(d) => d.clone(),
+        None => {
+          return nerr(100, "Invalid data");
+        }
+      };
+      // Checked up front so that none get added when one isn't allowed.
+      for entry in entries.iter() {
+        if let Err(e) = grant.require_entry(TokenScope::Admin, entry) {
+          audit::record(
+            rtodo,
+            AuditRecord::new(&origin, &grant)
+              .with_entry(&EntryIdentifier::Name(entry.name.clone()), None, None)
+              .with_error(&e),
+          );
+          return nerr(100, &e);
+        }
       }
-    };
-  }
-  nsucc(200, "succeed")
+      for entry in entries {
+        // By the id add_entry is about to assign.
+        let audit = EntryAudit::begin(
+          &origin,
+          &grant,
+          rtodo,
+          &EntryIdentifier::Id(rtodo.cur_entry_id + 1),
+        );
+        let res = rtodo.add_entry(entry);
+        audit.finish(rtodo, &res);
+        if let Err(e) = res {
+          return nerr(100, &format!("Failed to add entry: {}", e));
+        }
+      }
+      nsucc(200, "succeed")
+    })
+    .await
 }
 
-async fn delete_entries(data: ReqDataT<Vec<EntryIdentifier>>, state: RS) -> impl Responder {
-  let mut rtodo = get_rtodo_write_gurad(&state).await;
-  if !data.check_token(&rtodo) {
-    return nerr(100, "Invalid token");
-  }
-  for identifier in match &data.data {
-    Some(d) => d,
-    None => {
-      return nerr(100, "Invalid data");
-    }
-  } {
-    match rtodo.delete_entry(identifier) {
-      Ok(_) => {}
-      Err(e) => {
-        return nerr(100, &format!("Failed to delete entry: {}", e));
+#[utoipa::path(
+  post,
+  path = "/api/deleteEntries",
+  request_body = ReqCommonData<Vec<EntryIdentifier>>,
+  responses((status = 200, description = "Code 200 on success, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn delete_entries(
+  req: HttpRequest,
+  data: ReqDataT<Vec<EntryIdentifier>>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      let identifiers = match &data.data {
+        Some(d) => d,
+        None => {
+          return nerr(100, "Invalid data");
+        }
+      };
+      for identifier in identifiers {
+        if let Err(e) = rtodo.check_access(&grant, TokenScope::Admin, identifier) {
+          EntryAudit::begin(&origin, &grant, rtodo, identifier).finish(rtodo, &Err::<(), _>(&e));
+          return nerr(100, &e);
+        }
       }
-    };
-  }
-  nsucc(200, "succeed")
+      for identifier in identifiers {
+        let audit = EntryAudit::begin(&origin, &grant, rtodo, identifier);
+        let res = rtodo.delete_entry(identifier);
+        audit.finish(rtodo, &res);
+        if let Err(e) = res {
+          return nerr(100, &format!("Failed to delete entry: {}", e));
+        }
+      }
+      nsucc(200, "succeed")
+    })
+    .await
 }
 
-async fn get_works(data: ReqData, state: RS) -> impl Responder {
-  let rtodo = get_rtodo_read_gurad(&state).await;
-  if !check_token(&data, &rtodo) {
-    return nerr(100, "Invalid token");
-  }
-  nsucc(200, &rtodo.works)
+#[utoipa::path(
+  post,
+  path = "/api/getWorks",
+  request_body = ReqToken,
+  responses((status = 200, description = "Runtime state of all enabled entries", body = ResCommonData<Vec<Work>>))
+)]
+async fn get_works(data: ReqData, state: RS) -> Result<String, ApiError> {
+  state
+    .read(move |rtodo| {
+      let grant = match check_token(&data, rtodo, TokenScope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return nerr(100, &e),
+      };
+      nsucc(200, rtodo.get_works(&grant))
+    })
+    .await
 }
 
-async fn edit_entry(data: ReqDataT<Entry>, state: RS) -> impl Responder {
-  let mut rtodo = get_rtodo_write_gurad(&state).await;
-  if !data.check_token(&rtodo) {
-    return nerr(100, "Invalid token");
-  }
-  match &data.data {
-    Some(d) => match rtodo.edit_entry(d) {
-      Ok(_) => {
-        return nsucc(200, "succeed");
+#[utoipa::path(
+  post,
+  path = "/api/getLocks",
+  request_body = ReqToken,
+  responses((status = 200, description = "Declared locks and their holders", body = ResCommonData<Vec<LockState>>))
+)]
+async fn get_locks(data: ReqData, state: RS) -> Result<String, ApiError> {
+  state
+    .read(move |rtodo| {
+      let grant = match check_token(&data, rtodo, TokenScope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return nerr(100, &e),
+      };
+      let mut locks = rtodo.get_locks();
+      for lock in locks.iter_mut() {
+        lock
+          .holders
+          .retain(|holder| rtodo.covers(&grant, holder.entry_id));
+      }
+      nsucc(200, locks)
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/editEntry",
+  request_body = ReqCommonData<Entry>,
+  responses((status = 200, description = "Code 200 on success, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn edit_entry(
+  req: HttpRequest,
+  data: ReqDataT<Entry>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      match &data.data {
+        Some(d) => {
+          let audit = EntryAudit::begin(&origin, &grant, rtodo, &EntryIdentifier::Id(d.id));
+          let res = rtodo
+            .check_access(&grant, TokenScope::Admin, &EntryIdentifier::Id(d.id))
+            .and_then(|_| grant.require_entry(TokenScope::Admin, d))
+            .map_err(|e| e.into())
+            .and_then(|_| rtodo.edit_entry(d));
+          audit.finish(rtodo, &res);
+          match res {
+            Ok(_) => nsucc(200, "succeed"),
+            Err(e) => nerr(100, &format!("Failed to edit entry: {}", e)),
+          }
+        }
+        None => nerr(100, "Invalid data"),
       }
-      Err(e) => {
-        return nerr(100, &format!("Failed to edit entry: {}", e));
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/runEntry",
+  request_body = ReqCommonData<RunEntry>,
+  responses((status = 200, description = "Code 200 with what became of the run, 100 with the reason otherwise", body = ResCommonData<RunOutcome>))
+)]
+async fn run_entry(
+  req: HttpRequest,
+  data: ReqDataT<RunEntry>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      match &data.data {
+        Some(d) => {
+          let audit = EntryAudit::begin(&origin, &grant, rtodo, &d.entry);
+          let res = rtodo
+            .check_access(&grant, TokenScope::Operate, &d.entry)
+            .map_err(|e| e.into())
+            .and_then(|_| rtodo.run_entry(d));
+          audit.finish(rtodo, &res);
+          match res {
+            Ok(outcome) => nsucc(200, outcome),
+            Err(e) => nerr(100, &format!("Failed to run entry: {}", e)),
+          }
+        }
+        None => nerr(100, "Invalid data"),
       }
-    },
-    None => {
-      return nerr(100, "Invalid data");
-    }
-  };
+    })
+    .await
 }
 
-async fn stop_daemon(data: ReqData, state: RS) -> impl Responder {
-  let mut rtodo = get_rtodo_write_gurad(&state).await;
-  if !check_token(&data, &rtodo) {
-    return nerr(100, "Invalid token");
-  }
-  info!("Info: stopping daemon");
-  rtodo.stop_daemon();
-  std::process::exit(0);
-}
-
-pub fn start_server(rtodo: Arc<RwLock<Rtodo>>) -> () {
-  {
-    match rtodo.write() {
-      Ok(data) => {
-        #[cfg(debug_assertions)]
-        info!(
-          "Info: got write lock of works at line:{}, file: {}",
-          line!(),
-          file!()
-        );
-        data
-      }
-      Err(err) => {
-        error!(
-          "Error: Internal error: {}, line:{}, file: {}",
-          err,
-          line!(),
-          file!()
-        );
-        return;
+#[utoipa::path(
+  post,
+  path = "/api/pauseEntry",
+  request_body = ReqCommonData<PauseEntry>,
+  responses((status = 200, description = "Code 200 on success, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn pause_entry(
+  req: HttpRequest,
+  data: ReqDataT<PauseEntry>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      match &data.data {
+        Some(d) => {
+          let audit = EntryAudit::begin(&origin, &grant, rtodo, &d.entry);
+          let res = rtodo
+            .check_access(&grant, TokenScope::Operate, &d.entry)
+            .map_err(|e| e.into())
+            .and_then(|_| rtodo.pause_entry(d));
+          audit.finish(rtodo, &res);
+          match res {
+            Ok(_) => nsucc(200, "succeed"),
+            Err(e) => nerr(100, &format!("Failed to pause entry: {}", e)),
+          }
+        }
+        None => nerr(100, "Invalid data"),
       }
-    }
-    .executor_pid = {
-      #[cfg(target_family = "unix")]
-      nix::unistd::getpid().as_raw()
-    }
-  }
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/resumeEntry",
+  request_body = ReqCommonData<EntryIdentifier>,
+  responses((status = 200, description = "Code 200 on success, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn resume_entry(
+  req: HttpRequest,
+  data: ReqDataT<EntryIdentifier>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      match &data.data {
+        Some(d) => {
+          let audit = EntryAudit::begin(&origin, &grant, rtodo, d);
+          let res = rtodo
+            .check_access(&grant, TokenScope::Operate, d)
+            .map_err(|e| e.into())
+            .and_then(|_| rtodo.resume_entry(d));
+          audit.finish(rtodo, &res);
+          match res {
+            Ok(_) => nsucc(200, "succeed"),
+            Err(e) => nerr(100, &format!("Failed to resume entry: {}", e)),
+          }
+        }
+        None => nerr(100, "Invalid data"),
+      }
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/skipEntry",
+  request_body = ReqCommonData<EntryIdentifier>,
+  responses((status = 200, description = "Code 200 on success, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn skip_entry(
+  req: HttpRequest,
+  data: ReqDataT<EntryIdentifier>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      match &data.data {
+        Some(d) => {
+          let audit = EntryAudit::begin(&origin, &grant, rtodo, d);
+          let res = rtodo
+            .check_access(&grant, TokenScope::Operate, d)
+            .map_err(|e| e.into())
+            .and_then(|_| rtodo.skip_entry(d));
+          audit.finish(rtodo, &res);
+          match res {
+            Ok(_) => nsucc(200, "succeed"),
+            Err(e) => nerr(100, &format!("Failed to skip entry: {}", e)),
+          }
+        }
+        None => nerr(100, "Invalid data"),
+      }
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/reloadConfig",
+  request_body = ReqToken,
+  responses((status = 200, description = "Code 200 on success, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn reload_config(req: HttpRequest, data: ReqData, state: RS) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(request_token(&data)) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      let res = grant
+        .require_all(TokenScope::Admin)
+        .map_err(|e| e.into())
+        .and_then(|_| rtodo.reload_conf());
+      audit::record(rtodo, AuditRecord::new(&origin, &grant).with_result(&res));
+      match res {
+        Ok(_) => nsucc(200, "succeed"),
+        Err(e) => nerr(100, &format!("Failed to reload config: {}", e)),
+      }
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/createToken",
+  request_body = ReqCommonData<NewToken>,
+  responses((status = 200, description = "Code 200 with the token, which is not shown again, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn create_token(
+  req: HttpRequest,
+  data: ReqDataT<NewToken>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      let new = match &data.data {
+        Some(d) => d.clone(),
+        None => return nerr(100, "Invalid data"),
+      };
+      let record =
+        AuditRecord::new(&origin, &grant).with_change(None, serde_json::to_value(&new).ok());
+      let res = grant
+        .require_all(TokenScope::Admin)
+        .map_err(|e| e.into())
+        .and_then(|_| rtodo.create_token(new));
+      audit::record(rtodo, record.with_result(&res));
+      match res {
+        Ok(token) => nsucc(200, token),
+        Err(e) => nerr(100, &format!("Failed to create token: {}", e)),
+      }
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/listTokens",
+  request_body = ReqToken,
+  responses((status = 200, description = "Named tokens, without their hashes", body = ResCommonData<Vec<TokenInfo>>))
+)]
+async fn list_tokens(data: ReqData, state: RS) -> Result<String, ApiError> {
+  state
+    .read(move |rtodo| {
+      if let Err(e) = check_token(&data, rtodo, TokenScope::Admin)
+        .and_then(|grant| grant.require_all(TokenScope::Admin))
+      {
+        return nerr(100, &e);
+      }
+      nsucc(200, rtodo.list_tokens())
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/revokeToken",
+  request_body = ReqCommonData<String>,
+  responses((status = 200, description = "Code 200 on success, 100 with the reason otherwise", body = ResCommonData<String>))
+)]
+async fn revoke_token(
+  req: HttpRequest,
+  data: ReqDataT<String>,
+  state: RS,
+) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(&data.token) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      let name = match &data.data {
+        Some(d) => d,
+        None => return nerr(100, "Invalid data"),
+      };
+      let before = rtodo
+        .list_tokens()
+        .into_iter()
+        .find(|token| token.name == *name)
+        .and_then(|token| serde_json::to_value(token).ok());
+      let res = grant
+        .require_all(TokenScope::Admin)
+        .map_err(|e| e.into())
+        .and_then(|_| rtodo.revoke_token(name));
+      let record = AuditRecord::new(&origin, &grant).with_change(before, None);
+      audit::record(rtodo, record.with_result(&res));
+      match res {
+        Ok(_) => nsucc(200, "succeed"),
+        Err(e) => nerr(100, &format!("Failed to revoke token: {}", e)),
+      }
+    })
+    .await
+}
+
+#[utoipa::path(
+  post,
+  path = "/api/stopDaemon",
+  request_body = ReqToken,
+  responses((status = 200, description = "Code 200 once the daemon is stopping, it exits after dealing with running jobs per its shutdown config", body = ResCommonData<String>))
+)]
+async fn stop_daemon(req: HttpRequest, data: ReqData, state: RS) -> Result<String, ApiError> {
+  let origin = Origin::new(&req);
+  state
+    .write(move |rtodo| {
+      let grant = match rtodo.authenticate(request_token(&data)) {
+        Some(grant) => grant,
+        None => return nerr(100, "Invalid token"),
+      };
+      let res = grant.require_all(TokenScope::Admin);
+      audit::record(rtodo, AuditRecord::new(&origin, &grant).with_result(&res));
+      if let Err(e) = res {
+        return nerr(100, &e);
+      }
+      rtodo.stop_daemon();
+      nsucc(200, "stopping")
+    })
+    .await
+}
+
+/// `Logger::default()` without the query string, which may carry a token.
+fn access_logger() -> Logger {
+  Logger::new(r#"%a "%{request}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+    .custom_request_replace("request", |req| {
+      format!("{} {} {:?}", req.method(), req.path(), req.version())
+    })
+}
+
+pub fn start_server(core: Sender<Command>, listeners: Vec<Listener>) {
   let rt = Runtime::new().unwrap();
-  let addr = rtodo.read().unwrap().config.address.clone();
   rt.block_on(async {
-    let state = web::Data::new(RtodoState {
-      rtodo: rtodo.clone(),
-    });
-    let server = HttpServer::new(move || {
+    let state = web::Data::new(RtodoState { core });
+    let (addr, tcp_enabled, socket_path, socket_mode, socket_access, tls, metrics_addr) = state
+      .read(|rtodo| {
+        let config = &rtodo.config;
+        (
+          config.address.clone(),
+          config.tcp_enabled,
+          config.socket_path.clone(),
+          config.socket_mode.clone(),
+          config.socket_access.clone(),
+          config.tls.clone(),
+          config.metrics.address.clone(),
+        )
+      })
+      .await
+      .unwrap_or_else(|err| panic!("Error: {}", err));
+    if let Some(metrics_addr) = metrics_addr {
+      let state = state.clone();
+      let metrics_server = HttpServer::new(move || {
+        App::new()
+          .app_data(state.clone())
+          .service(web::resource("/metrics").route(web::get().to(metrics::get_metrics)))
+      })
+      .workers(1)
+      .disable_signals()
+      .bind(&metrics_addr)
+      .unwrap_or_else(|err| {
+        panic!(
+          "Error: Failed to bind metrics address: {}, Error: {}",
+          metrics_addr, err
+        )
+      })
+      .run();
+      info!("Info: Serving metrics at {}", metrics_addr);
+      tokio::spawn(metrics_server);
+    }
+    let mut server = HttpServer::new(move || {
+      let socket_access = socket_access.clone();
       App::new()
         .wrap(Cors::default().allow_any_origin())
-        .wrap(Logger::default())
+        .wrap(access_logger())
         .wrap(Logger::new("%a"))
+        .wrap_fn(move |req, srv| {
+          let denied = req
+            .conn_data::<SocketPeer>()
+            .is_some_and(|peer| !socket_access.allows(peer));
+          let res = if denied { Err(req) } else { Ok(srv.call(req)) };
+          async move {
+            match res {
+              Ok(res) => Ok(res.await?.map_into_left_body()),
+              Err(req) => Ok(
+                req
+                  .into_response(
+                    ApiError::new(ApiErrorKind::Forbidden, "Peer is not allowed on the socket")
+                      .error_response(),
+                  )
+                  .map_into_right_body(),
+              ),
+            }
+          }
+        })
+        .wrap_fn(|req, srv| {
+          let method = req.method().clone();
+          let route = req
+            .match_pattern()
+            .unwrap_or_else(|| String::from("unmatched"));
+          let res = srv.call(req);
+          async move {
+            let res = res.await?;
+            metrics::count_request(&method, &route, res.status().as_u16());
+            Ok(res)
+          }
+        })
         .app_data(state.clone())
+        .service(web::scope("/api/v1").configure(api_v1::configure))
         .service(
           web::scope("/api")
-            .route(
-              "/",
-              web::get().to(|| async { String::from("Hello, rtodo!") }),
-            )
+            .route("/", web::get().to(api_index))
+            .route("/openapi.json", web::get().to(openapi::openapi_json))
             .route("/validateToken", web::post().to(validate_token))
             .route("/getEntries", web::post().to(get_entries))
             .route("/getWorks", web::post().to(get_works))
+            .route("/getLocks", web::post().to(get_locks))
             .route("/addEntries", web::post().to(add_entries))
             .route("/deleteEntries", web::post().to(delete_entries))
             .route("/editEntry", web::post().to(edit_entry))
+            .route("/runEntry", web::post().to(run_entry))
+            .route("/pauseEntry", web::post().to(pause_entry))
+            .route("/resumeEntry", web::post().to(resume_entry))
+            .route("/skipEntry", web::post().to(skip_entry))
+            .route("/reloadConfig", web::post().to(reload_config))
+            .route("/createToken", web::post().to(create_token))
+            .route("/listTokens", web::post().to(list_tokens))
+            .route("/revokeToken", web::post().to(revoke_token))
+            .route("/audit", web::get().to(audit::get_audit))
+            .route("/events", web::get().to(events::sse))
+            .route("/events/ws", web::get().to(events::websocket))
+            .route("/runs", web::get().to(runs::get_runs))
+            .route("/runs/{id}/log", web::get().to(runs::run_log))
             .route("/stopDaemon", web::post().to(stop_daemon)),
         )
+        .service(web::resource("/metrics").route(web::get().to(metrics::get_metrics)))
+        .service(web::resource("/healthz").route(web::get().to(health::healthz)))
+        .service(web::resource("/readyz").route(web::get().to(health::readyz)))
         .service(web::resource("/").route(web::get().to(hello)))
     })
-    .bind(&addr)
-    .unwrap_or_else(|err| panic!("Error: Failed to bind address: {}, Error: {}", addr, err))
-    .run();
-    info!("Info: Server started at {}", addr);
+    .on_connect(|conn, data| {
+      if let Some(stream) = conn.downcast_ref::<UnixStream>() {
+        data.insert(SocketPeer(stream.peer_cred().ok()));
+      }
+    })
+    // The daemon stops once running jobs are dealt with, not right away.
+    .disable_signals();
+    let tls_config = tls.as_ref().map(|tls| {
+      let (config, reloader) = tls::server_config(tls)
+        .unwrap_or_else(|err| panic!("Error: Failed to set up TLS, Error: {}", err));
+      tokio::spawn(tls::reload_on_hangup(reloader));
+      config
+    });
+    let activated = !listeners.is_empty();
+    if activated {
+      // Socket activated, systemd bound the sockets for us.
+      info!(
+        "Info: Listening on {} sockets from systemd",
+        listeners.len()
+      );
+      for listener in listeners {
+        server = match listener {
+          Listener::Tcp(listener) => match &tls_config {
+            Some(config) => server.listen_rustls_0_23(listener, config.clone()),
+            None => server.listen(listener),
+          },
+          Listener::Unix(listener) => server.listen_uds(listener),
+        }
+        .unwrap_or_else(|err| {
+          panic!(
+            "Error: Failed to listen on socket from systemd, Error: {}",
+            err
+          )
+        });
+      }
+    } else if tcp_enabled {
+      server = match &tls_config {
+        Some(config) => server.bind_rustls_0_23(&addr, config.clone()),
+        None => server.bind(&addr),
+      }
+      .unwrap_or_else(|err| panic!("Error: Failed to bind address: {}, Error: {}", addr, err));
+      info!(
+        "Info: Listening at {}{}",
+        addr,
+        if tls.is_some() { " with TLS" } else { "" }
+      );
+    }
+    match socket_path {
+      _ if activated => (),
+      Some(path) => match bind_socket(&path, &socket_mode) {
+        Ok(listener) => {
+          server = server.listen_uds(listener).unwrap_or_else(|err| {
+            panic!(
+              "Error: Failed to listen on socket: {:?}, Error: {}",
+              path, err
+            )
+          });
+          info!("Info: Listening on socket {:?}", path);
+        }
+        // Unprivileged daemons can't create the default socket, TCP still works for them.
+        Err(err) if tcp_enabled => {
+          error!("Error: Failed to bind socket: {:?}, Error: {}", path, err)
+        }
+        Err(err) => panic!("Error: Failed to bind socket: {:?}, Error: {}", path, err),
+      },
+      None if !tcp_enabled => {
+        panic!("Error: TCP is disabled and no socket_path is set, nothing to listen on")
+      }
+      None => (),
+    }
+    let server = server.run();
+    info!("Info: Server started");
+    systemd::notify("READY=1");
     server.await.unwrap_or_else(|err| {
       error!("Error: Server error: {}", err);
     });
   })
 }
+
+fn bind_socket(path: &Path, mode: &str) -> Result<UnixListener, Box<dyn Error>> {
+  let mode = u32::from_str_radix(mode, 8)?;
+  if StdUnixStream::connect(path).is_ok() {
+    return Err("Socket is in use, is another daemon running?".into());
+  }
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  // A socket left behind by a daemon that didn't shut down cleanly, anything
+  // else there isn't ours to delete.
+  match fs::symlink_metadata(path) {
+    Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+    Ok(_) => return Err(format!("{:?} exists and is not a socket", path).into()),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+    Err(err) => return Err(err.into()),
+  }
+  let socket = unsafe {
+    OwnedFd::from_raw_fd(socket(
+      AddressFamily::Unix,
+      SockType::Stream,
+      SockFlag::SOCK_CLOEXEC,
+      None,
+    )?)
+  };
+  // The file bind creates gets the socket's mode less the umask, so it is
+  // never more accessible than `mode`. Unlike a umask, this doesn't leak into
+  // jobs the core spawns meanwhile.
+  fchmod(socket.as_raw_fd(), Mode::from_bits_truncate(mode))?;
+  bind(socket.as_raw_fd(), &UnixAddr::new(path)?)?;
+  listen(socket.as_raw_fd(), 1024)?;
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+  Ok(UnixListener::from(socket))
+}
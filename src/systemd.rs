@@ -0,0 +1,234 @@
+use crate::daemon::KILL_GRACE;
+use crate::health;
+use crate::types::*;
+
+use log::error;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// The first socket passed by systemd, see sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// What systemd told the daemon through its environment.
+struct Manager {
+  socket: Option<UnixDatagram>,
+  /// Half of `WATCHDOG_USEC`, as systemd recommends.
+  watchdog: Option<Duration>,
+}
+
+static MANAGER: OnceLock<Manager> = OnceLock::new();
+
+/// A socket passed by systemd, see `init`.
+pub enum Listener {
+  Tcp(std::net::TcpListener),
+  Unix(std::os::unix::net::UnixListener),
+}
+
+/// Whether a variable like `WATCHDOG_PID` names us, or is unset.
+fn for_us(pid: Option<String>) -> bool {
+  pid.is_none_or(|pid| pid.parse() == Ok(process::id()))
+}
+
+fn connect(path: &str) -> Result<UnixDatagram, Box<dyn Error>> {
+  let socket = UnixDatagram::unbound()?;
+  match path.strip_prefix('@') {
+    Some(name) => socket.connect_addr(&SocketAddr::from_abstract_name(name)?)?,
+    None => socket.connect(path)?,
+  }
+  Ok(socket)
+}
+
+/// Reads and clears what systemd set in the environment, so that jobs don't
+/// inherit it. Returns the sockets passed for socket activation. Run it before
+/// the daemon's threads start.
+pub fn init() -> Vec<Listener> {
+  let socket = env::var("NOTIFY_SOCKET").ok().and_then(|path| {
+    connect(&path)
+      .inspect_err(|err| {
+        error!(
+          "Error: Cannot connect to NOTIFY_SOCKET {}, Err: {}",
+          path, err
+        )
+      })
+      .ok()
+  });
+  let watchdog = env::var("WATCHDOG_USEC")
+    .ok()
+    .filter(|_| for_us(env::var("WATCHDOG_PID").ok()))
+    .and_then(|usec| usec.parse().ok())
+    .map(|usec: u64| Duration::from_micros(usec / 2));
+  let _ = MANAGER.set(Manager { socket, watchdog });
+  let fds = env::var("LISTEN_FDS")
+    .ok()
+    .filter(|_| for_us(env::var("LISTEN_PID").ok()))
+    .and_then(|fds| fds.parse().ok())
+    .unwrap_or(0);
+  for name in [
+    "NOTIFY_SOCKET",
+    "WATCHDOG_USEC",
+    "WATCHDOG_PID",
+    "LISTEN_FDS",
+    "LISTEN_PID",
+    "LISTEN_FDNAMES",
+  ] {
+    env::remove_var(name);
+  }
+  (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+    .filter_map(|fd| match listen_fd(fd) {
+      Ok(listener) => Some(listener),
+      Err(err) => {
+        error!(
+          "Error: Cannot use socket {} passed by systemd, Err: {}",
+          fd, err
+        );
+        None
+      }
+    })
+    .collect()
+}
+
+fn listen_fd(fd: RawFd) -> Result<Listener, Box<dyn Error>> {
+  // Jobs would keep the socket open otherwise.
+  fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+  let family = getsockname::<SockaddrStorage>(fd)?.family();
+  // Passed to us alone, so owning it is sound.
+  Ok(match family {
+    Some(AddressFamily::Unix) => {
+      Listener::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+    }
+    Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+      Listener::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+    }
+    family => return Err(format!("Unsupported address family {:?}", family).into()),
+  })
+}
+
+/// Sends `state` to systemd, e.g. `READY=1`, nothing happens when it isn't
+/// supervising the daemon.
+pub fn notify(state: &str) {
+  if let Some(socket) = MANAGER.get().and_then(|manager| manager.socket.as_ref()) {
+    if let Err(err) = socket.send(state.as_bytes()) {
+      error!("Error: Failed to notify systemd of {}, Err: {}", state, err);
+    }
+  }
+}
+
+/// Kept by the core, which reports to systemd on each pass.
+#[derive(Default)]
+pub struct Reporter {
+  last_ping: Option<Instant>,
+  status: String,
+}
+
+impl Reporter {
+  /// Pings the watchdog while the daemon is healthy, and updates the status
+  /// line when it changed.
+  pub fn report(&mut self, rtodo: &Rtodo) {
+    let manager = match MANAGER.get() {
+      Some(manager) if manager.socket.is_some() => manager,
+      _ => return,
+    };
+    if let Some(interval) = manager.watchdog {
+      let due = self.last_ping.is_none_or(|last| last.elapsed() >= interval);
+      if due && health::is_healthy() {
+        notify("WATCHDOG=1");
+        self.last_ping = Some(Instant::now());
+      }
+    }
+    let status = status(rtodo);
+    if status != self.status {
+      notify(&format!("STATUS={}", status));
+      self.status = status;
+    }
+  }
+}
+
+fn status(rtodo: &Rtodo) -> String {
+  let counts = rtodo.job_counts();
+  let running = rtodo
+    .works
+    .iter()
+    .filter(|work| !work.running_processes.is_empty())
+    .count();
+  let status = format!(
+    "{} of {} works running, {} jobs, {} queued",
+    running,
+    rtodo.works.len(),
+    counts.running,
+    counts.queued
+  );
+  match rtodo.is_stopping() {
+    true => format!("Stopping, {}", status),
+    false => status,
+  }
+}
+
+/// A service unit running the daemon of `rtodo`'s config.
+pub fn service_unit(rtodo: &Rtodo) -> Result<String, Box<dyn Error>> {
+  let exe = env::current_exe()?;
+  let conf = fs::canonicalize(&rtodo.conf_path)?;
+  let shutdown = &rtodo.config.shutdown;
+  // Detached jobs must outlive the service, the daemon deals with the others
+  // before systemd would.
+  let kill_mode = match shutdown.on_timeout {
+    ShutdownPolicy::Detach => "process",
+    ShutdownPolicy::Terminate | ShutdownPolicy::Kill => "mixed",
+  };
+  let stop_timeout = shutdown.timeout + 2 * KILL_GRACE.as_secs() + 5;
+  // Lets the daemon create the cgroups of runs and limit them.
+  let delegate = match rtodo.config.cgroup {
+    Some(_) => "Delegate=cpu memory pids\n",
+    None => "",
+  };
+  Ok(format!(
+    "[Unit]
+Description=rtodo scheduled tasks daemon
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart={} start-daemon --conf {}
+Restart=on-failure
+WatchdogSec=30
+TimeoutStopSec={}
+KillMode={}
+{}
+[Install]
+WantedBy=multi-user.target
+",
+    exe.display(),
+    conf.display(),
+    stop_timeout,
+    kill_mode,
+    delegate
+  ))
+}
+
+/// A socket unit passing the daemon's listening sockets, to go with the
+/// service unit of the same name.
+pub fn socket_unit(rtodo: &Rtodo) -> String {
+  let config = &rtodo.config;
+  let mut unit = String::from("[Unit]\nDescription=rtodo API sockets\n\n[Socket]\n");
+  if config.tcp_enabled {
+    unit.push_str(&format!("ListenStream={}\n", config.address));
+  }
+  if let Some(path) = &config.socket_path {
+    unit.push_str(&format!(
+      "ListenStream={}\nSocketMode=0{}\n",
+      path.display(),
+      config.socket_mode
+    ));
+  }
+  unit.push_str("\n[Install]\nWantedBy=sockets.target\n");
+  unit
+}
@@ -0,0 +1,99 @@
+use crate::types::*;
+
+use log::{error, info};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Hands out the certificate loaded last, so that it can be replaced without
+/// restarting the listener.
+#[derive(Debug)]
+pub struct CertReloader {
+  cert: PathBuf,
+  key: PathBuf,
+  provider: Arc<CryptoProvider>,
+  certified: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertReloader {
+  fn load(
+    cert: &Path,
+    key: &Path,
+    provider: &CryptoProvider,
+  ) -> Result<CertifiedKey, Box<dyn Error>> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    Ok(CertifiedKey::from_der(certs, key, provider)?)
+  }
+
+  pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+    let certified = Self::load(&self.cert, &self.key, &self.provider)?;
+    *self.certified.write().map_err(|err| err.to_string())? = Arc::new(certified);
+    Ok(())
+  }
+}
+
+impl ResolvesServerCert for CertReloader {
+  fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    self
+      .certified
+      .read()
+      .ok()
+      .map(|certified| certified.clone())
+  }
+}
+
+fn root_store(path: &Path) -> Result<RootCertStore, Box<dyn Error>> {
+  let mut roots = RootCertStore::empty();
+  for cert in CertificateDer::pem_file_iter(path)? {
+    roots.add(cert?)?;
+  }
+  Ok(roots)
+}
+
+pub fn server_config(tls: &TlsConfig) -> Result<(ServerConfig, Arc<CertReloader>), Box<dyn Error>> {
+  let provider = Arc::new(ring::default_provider());
+  let reloader = Arc::new(CertReloader {
+    certified: RwLock::new(Arc::new(CertReloader::load(
+      &tls.cert, &tls.key, &provider,
+    )?)),
+    cert: tls.cert.clone(),
+    key: tls.key.clone(),
+    provider: provider.clone(),
+  });
+  let builder =
+    ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+  let builder = match &tls.client_ca {
+    Some(client_ca) => builder.with_client_cert_verifier(
+      WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(client_ca)?), provider)
+        .build()?,
+    ),
+    None => builder.with_no_client_auth(),
+  };
+  Ok((builder.with_cert_resolver(reloader.clone()), reloader))
+}
+
+pub async fn reload_on_hangup(reloader: Arc<CertReloader>) {
+  let mut hangup = match signal(SignalKind::hangup()) {
+    Ok(data) => data,
+    Err(err) => {
+      error!("Error: Failed to listen for SIGHUP, Err: {}", err);
+      return;
+    }
+  };
+  while hangup.recv().await.is_some() {
+    match reloader.reload() {
+      Ok(_) => info!("Info: Reloaded TLS certificate"),
+      Err(err) => error!(
+        "Error: Failed to reload TLS certificate, keeping the old one, Err: {}",
+        err
+      ),
+    }
+  }
+}
@@ -1,41 +1,89 @@
 use actix_web::web;
 use serde::{Deserialize, Serialize};
 use std::{
-  collections::HashMap,
+  collections::{HashMap, VecDeque},
   path::PathBuf,
-  sync::{Arc, RwLock},
+  sync::{atomic::AtomicU64, mpsc::Sender},
 };
+use utoipa::{IntoParams, ToSchema};
 
 pub type RS = web::Data<RtodoState>;
-pub type ReqData = web::Json<serde_json::Value>;
+pub type ReqData = web::Json<ReqToken>;
 pub type ReqDataT<T> = web::Json<ReqCommonData<T>>;
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Body of the legacy routes that only need the token.
+#[derive(Deserialize, ToSchema)]
+pub struct ReqToken {
+  #[serde(default)]
+  pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct ReqCommonData<T> {
   pub token: String,
   pub data: Option<T>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct ResCommonData<T> {
   pub code: i32,
   pub data: T,
 }
 
+#[derive(Serialize, Clone, Copy, Debug, ToSchema)]
+pub enum ApiErrorKind {
+  Unauthorized,
+  Forbidden,
+  NotFound,
+  Conflict,
+  Invalid,
+  OutOfRange,
+  Internal,
+}
+
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct ApiError {
+  pub kind: ApiErrorKind,
+  pub message: String,
+}
+
 pub struct RtodoState {
-  pub rtodo: Arc<RwLock<Rtodo>>,
+  pub core: Sender<Command>,
+}
+
+/// What the daemon's core is asked to do. The core is the only thread that
+/// touches `Rtodo`, so each command sees it as the previous one left it.
+pub enum Command {
+  Read(Box<dyn FnOnce(&Rtodo) + Send>),
+  /// Works may change, their schedule is read again afterwards.
+  Write(Box<dyn FnOnce(&mut Rtodo) + Send>),
+  /// Sent by the reaper when child processes exited.
+  ChildExited,
+  /// Sent on SIGTERM and SIGINT.
+  Stop,
+}
+
+/// Who sent a request, taken out of it since requests can't leave their worker.
+#[derive(Clone)]
+pub struct Origin {
+  /// Peer address, or the uid of a peer on the Unix socket.
+  pub client: String,
+  /// Method and path of the request, e.g. `POST /api/pauseEntry`.
+  pub operation: String,
 }
 
 pub struct Rtodo {
   pub config: Config,
-  pub works: Vec<RwLock<Work>>,
+  pub works: Vec<Work>,
   pub cur_entry_id: u32,
   pub conf_path: String,
-  pub executor_pid: i32,
-  pub checker_pid: i32,
+  #[allow(dead_code)]
   pub server_pid: i32,
   pub daemon_status: RtodoDaemonStatus,
   pub rcli: reqwest::blocking::Client,
+  /// Base URL of the daemon's API, e.g. `https://host:6472/api`.
+  pub api_url: String,
+  pub queue_seq: AtomicU64,
 }
 
 pub enum RtodoDaemonStatus {
@@ -49,9 +97,194 @@ pub struct Config {
   pub entries: Vec<Entry>,
   pub address: String,
   pub token: String,
+  #[serde(default)]
+  pub max_concurrent_jobs: Option<u32>,
+  #[serde(default = "Config::default_max_queued_jobs")]
+  pub max_queued_jobs: usize,
+  /// Capacity of each named lock, locks not listed here are mutexes.
+  #[serde(default)]
+  pub locks: HashMap<String, u32>,
+  /// Set to false to only listen on `socket_path`.
+  #[serde(default = "Config::default_tcp_enabled")]
+  pub tcp_enabled: bool,
+  /// Unix socket the daemon also listens on, the CLI prefers it over TCP.
+  #[serde(default = "Config::default_socket_path")]
+  pub socket_path: Option<PathBuf>,
+  /// Octal file mode of the socket, e.g. "660" to let its group connect.
+  #[serde(default = "Config::default_socket_mode")]
+  pub socket_mode: String,
+  #[serde(default)]
+  pub socket_access: SocketAccess,
+  /// Serve HTTPS instead of plain HTTP on `address`.
+  #[serde(default)]
+  pub tls: Option<TlsConfig>,
+  /// Named tokens besides `token`, managed with `rtodo token`.
+  #[serde(default)]
+  pub tokens: Vec<ApiToken>,
+  #[serde(default)]
+  pub audit: AuditConfig,
+  #[serde(default)]
+  pub metrics: MetricsConfig,
+  #[serde(default)]
+  pub shutdown: ShutdownConfig,
+  /// Locked by the running daemon so that only one runs per config. Defaults
+  /// to `rtodo.pid` beside the config file.
+  #[serde(default)]
+  pub pid_file: Option<PathBuf>,
+  /// Holds the captured output and inline scripts of runs, only the daemon's
+  /// user may enter it. Defaults to `runs` beside the config file.
+  #[serde(default)]
+  pub output_dir: Option<PathBuf>,
+  /// A cgroup v2 directory delegated to the daemon. Each run then gets a
+  /// cgroup in it, and is running while any process in there is alive, even
+  /// one that left the run's session. Needed for the cgroup limits of
+  /// `Limits`, with the cpu, memory and pids controllers delegated. Defaults
+  /// to the daemon's own cgroup when systemd delegated it.
+  #[serde(default)]
+  pub cgroup: Option<PathBuf>,
+}
+
+/// How the daemon stops on SIGTERM, SIGINT or `/api/stopDaemon`. It starts no
+/// new runs and waits for the running ones before exiting.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ShutdownConfig {
+  /// Seconds to wait for running jobs.
+  #[serde(default = "ShutdownConfig::default_timeout")]
+  pub timeout: u64,
+  /// What happens to jobs still running after `timeout`.
+  #[serde(default)]
+  pub on_timeout: ShutdownPolicy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum ShutdownPolicy {
+  /// Leave them running without the daemon.
+  #[default]
+  Detach,
+  /// Send SIGTERM, then SIGKILL to those still running after a grace period.
+  Terminate,
+  /// Send SIGKILL.
+  Kill,
+}
+
+/// Prometheus metrics, served at `/metrics`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MetricsConfig {
+  /// Let scrapers in without a token.
+  #[serde(default)]
+  pub public: bool,
+  /// Also serve `/metrics` alone over plain HTTP on this address, e.g. one
+  /// only Prometheus can reach.
+  #[serde(default)]
+  pub address: Option<String>,
+}
+
+/// Log of the API operations that change something, one JSON record per line.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditConfig {
+  #[serde(default = "AuditConfig::default_enabled")]
+  pub enabled: bool,
+  /// Defaults to `audit.log` beside the config file.
+  #[serde(default)]
+  pub path: Option<PathBuf>,
+  /// The log is rotated once it grows past this many bytes.
+  #[serde(default = "AuditConfig::default_max_size")]
+  pub max_size: u64,
+  /// Rotated logs kept, `.1` being the newest.
+  #[serde(default = "AuditConfig::default_keep")]
+  pub keep: u32,
+}
+
+/// What a token may do, each scope includes the ones before it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd, Debug, ToSchema)]
+pub enum TokenScope {
+  /// Get entries, works, locks, runs, logs and events.
+  Read,
+  /// Also run, pause, resume and skip entries.
+  Operate,
+  /// Also add, edit and delete entries, reload the config, stop the daemon
+  /// and manage tokens.
+  Admin,
+}
+
+/// A named API token, only a salted hash of it is kept.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiToken {
+  pub name: String,
+  pub scope: TokenScope,
+  /// Entry ids or names the token is limited to, along with the entries
+  /// having one of `tags`. The token covers all entries when both are empty.
+  #[serde(default)]
+  pub entries: Vec<String>,
+  #[serde(default)]
+  pub tags: Vec<String>,
+  pub created_at: DateTime,
+  pub salt: String,
+  /// Hex SHA-256 of the salt followed by the token.
+  pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct NewToken {
+  pub name: String,
+  pub scope: TokenScope,
+  #[serde(default)]
+  pub entries: Vec<String>,
+  #[serde(default)]
+  pub tags: Vec<String>,
+}
+
+/// A token as listed, without its hash.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct TokenInfo {
+  pub name: String,
+  pub scope: TokenScope,
+  pub entries: Vec<String>,
+  pub tags: Vec<String>,
+  pub created_at: DateTime,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default, Copy)]
+/// What the token of a request allows.
+pub struct Grant {
+  pub name: String,
+  pub scope: TokenScope,
+  pub entries: Vec<String>,
+  pub tags: Vec<String>,
+}
+
+/// PEM files for the TCP listener and for the CLI talking to it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TlsConfig {
+  /// Certificate chain and key of the daemon, reloaded on SIGHUP.
+  pub cert: PathBuf,
+  pub key: PathBuf,
+  /// When set, clients must present a certificate signed by one of these CAs.
+  #[serde(default)]
+  pub client_ca: Option<PathBuf>,
+  /// CAs the CLI trusts besides the system roots, e.g. for a self-signed `cert`.
+  #[serde(default)]
+  pub ca: Option<PathBuf>,
+  /// Certificate and key the CLI presents when `client_ca` is set.
+  #[serde(default)]
+  pub client_cert: Option<PathBuf>,
+  #[serde(default)]
+  pub client_key: Option<PathBuf>,
+  /// Name the CLI expects in `cert`, it still connects to `address`.
+  #[serde(default)]
+  pub server_name: Option<String>,
+}
+
+/// Users and groups allowed on the socket besides root and the daemon's own
+/// user, checked against the peer credentials of each connection.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SocketAccess {
+  #[serde(default)]
+  pub uids: Vec<u32>,
+  #[serde(default)]
+  pub gids: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Copy, PartialEq, Debug, ToSchema)]
 pub enum Status {
   Error,
   Running,
@@ -60,14 +293,14 @@ pub enum Status {
   Pending,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub enum TimeZone {
   Utc,
   Local,
   Offset(i8),
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct DateTime {
   pub year: i32,
   pub month: u32,
@@ -94,7 +327,7 @@ impl Default for DateTime {
   }
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub struct Duration {
   pub year: i32,
   pub month: u32,
@@ -105,40 +338,300 @@ pub struct Duration {
   pub total_sec: u64,
 }
 
-#[derive(Serialize, Clone)]
+/// How a process exited, the error says why it failed.
+pub type ExitResult = Result<(), String>;
+
+#[derive(Serialize, Clone, ToSchema)]
 pub struct Process {
   pub pid: i32,
+  pub run_id: u64,
+  pub started_at: DateTime,
+  #[schema(value_type = Option<String>)]
   pub output_tmp_file: Option<PathBuf>,
+  /// Size of the output file when the run started, its output follows.
+  pub output_start: u64,
+  /// Cgroup of the run, when the daemon tracks runs by cgroup.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[schema(value_type = Option<String>)]
+  pub cgroup: Option<PathBuf>,
+  /// How the run's first process exited, the run goes on while others of it
+  /// are alive.
+  #[serde(skip)]
+  #[schema(ignore)]
+  pub exit: Option<ExitResult>,
+  /// Where an inline script was written for the run, removed once it ends.
+  #[serde(skip)]
+  #[schema(ignore)]
+  pub script_file: Option<PathBuf>,
+}
+
+/// A run that has exited, kept so that its output can still be read.
+#[derive(Serialize, Clone, ToSchema)]
+pub struct FinishedRun {
+  pub process: Process,
+  pub finished_at: DateTime,
+  /// Why the run failed, `None` if it succeeded.
+  pub error: Option<String>,
+  /// Limits the run ran into, see `Limits`.
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub limits_hit: Vec<String>,
+  /// Size of the output file when the run ended, its output stops there.
+  pub output_end: Option<u64>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct RunInfo {
+  pub id: u64,
+  pub entry_id: u32,
+  pub entry_name: String,
+  pub pid: i32,
+  pub started_at: DateTime,
+  /// `None` while the run is still going.
+  pub finished_at: Option<DateTime>,
+  pub error: Option<String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub limits_hit: Vec<String>,
+  #[schema(value_type = Option<String>)]
+  pub output_file: Option<PathBuf>,
+  /// Where the output of the run lies in `output_file`, which other runs of
+  /// an entry logging to a file of its own share. `output_end` is `None`
+  /// while the run is going.
+  #[serde(default)]
+  pub output_start: u64,
+  #[serde(default)]
+  pub output_end: Option<u64>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
 pub struct Work {
   pub status: Status,
   pub entry: Entry,
   pub trigger_state: TriggerState,
   pub running_processes: Vec<Process>,
+  #[schema(value_type = Vec<QueuedRun>)]
+  pub queued_runs: VecDeque<QueuedRun>,
+  #[schema(value_type = Vec<FinishedRun>)]
+  pub finished_runs: VecDeque<FinishedRun>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Something that happened in the daemon, streamed to `/api/events` subscribers.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct Event {
+  pub time: DateTime,
+  pub entry_id: Option<u32>,
+  pub entry_name: Option<String>,
+  /// Only used to check which subscribers may see the event.
+  #[serde(skip)]
+  pub entry_tags: Vec<String>,
+  pub kind: EventKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub enum EventKind {
+  RunStarted {
+    run_id: u64,
+    pid: i32,
+  },
+  RunFinished {
+    run_id: u64,
+    pid: i32,
+  },
+  /// A run could not be spawned (no run id or pid) or exited unsuccessfully.
+  RunFailed {
+    run_id: Option<u64>,
+    pid: Option<i32>,
+    reason: String,
+  },
+  StatusChanged {
+    from: Status,
+    to: Status,
+  },
+  EntryAdded,
+  EntryEdited,
+  EntryDeleted,
+  ConfigReloaded,
+}
+
+/// Query of the event streams and run listing, `entry` is a comma separated
+/// list of ids or names.
+#[derive(Deserialize, IntoParams)]
+pub struct EntryFilter {
+  pub entry: Option<String>,
+}
+
+/// Token of the event streams for clients that can't set the `Authorization`
+/// header, like browsers. Only read without the header.
+#[derive(Deserialize, IntoParams)]
+pub struct TokenQuery {
+  pub token: Option<String>,
+}
+
+/// Who did what, only requests with a valid token are recorded.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct AuditRecord {
+  pub time: DateTime,
+  /// Peer address, or the uid of a peer on the Unix socket.
+  pub client: String,
+  /// Name of the token, `default` for the shared token of the config.
+  pub token: String,
+  /// Method and path of the request, e.g. `POST /api/pauseEntry`.
+  pub operation: String,
+  pub entry: Option<String>,
+  /// Fields that changed, as they were before and after the operation.
+  pub before: Option<serde_json::Value>,
+  pub after: Option<serde_json::Value>,
+  /// Why the operation was denied or failed.
+  pub error: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct AuditQuery {
+  /// Only records of this entry name.
+  pub entry: Option<String>,
+  /// Only records of this token name.
+  pub by: Option<String>,
+  /// Most recent records returned, 100 by default.
+  pub limit: Option<usize>,
+}
+
+/// Liveness of one of the daemon's loops.
+#[derive(Serialize, Clone, ToSchema)]
+pub struct LoopHealth {
+  /// Whether the loop made progress recently, or is still starting.
+  pub alive: bool,
+  /// Unix timestamp of the last pass over the works, none before the first.
+  pub last_heartbeat: Option<i64>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct Health {
+  /// `ok`, or why the daemon isn't healthy or ready.
+  pub status: String,
+  pub core: LoopHealth,
+  pub reaper: LoopHealth,
+}
+
+/// Query of a run's log, a `Range` header takes precedence over `offset` and `length`.
+#[derive(Deserialize, IntoParams)]
+pub struct LogQuery {
+  pub offset: Option<u64>,
+  pub length: Option<u64>,
+  /// Keep streaming output until the run finishes.
+  #[serde(default)]
+  pub follow: bool,
+}
+
+/// Credentials of a peer connected over the Unix socket, `None` when the
+/// kernel didn't tell.
+#[derive(Clone)]
+pub struct SocketPeer(pub Option<tokio::net::unix::UCred>);
+
+#[derive(Default)]
+pub struct JobCounts {
+  pub running: usize,
+  pub queued: usize,
+  pub locks: HashMap<String, usize>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct LockHolder {
+  pub entry_id: u32,
+  pub entry_name: String,
+  pub pids: Vec<i32>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct LockState {
+  pub name: String,
+  pub capacity: usize,
+  pub holders: Vec<LockHolder>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct QueuedRun {
+  pub seq: u64,
+  pub queued_at: DateTime,
+  pub run: RunEntry,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub enum EntryIdentifier {
   Id(u32),
   Name(String),
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct PauseEntry {
+  pub entry: EntryIdentifier,
+  pub until: Option<DateTime>,
+}
+
+pub struct LogsEntry {
+  pub entry: EntryIdentifier,
+  pub run: Option<u64>,
+  pub follow: bool,
+}
+
+pub enum UnitKind {
+  Service,
+  Socket,
+}
+
+pub struct DaemonOptions {
+  /// Run in the background, logging to `log_file`.
+  pub detach: bool,
+  /// Defaults to `rtodo.log` beside the config file.
+  pub log_file: Option<PathBuf>,
+}
+
+/// What a running daemon says about itself.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct DaemonInfo {
+  pub pid: u32,
+  pub version: String,
+  /// Unix timestamp of the daemon's start.
+  pub started_at: i64,
+  /// Seconds since `started_at`.
+  pub uptime: i64,
+  /// Waiting for running jobs before it exits.
+  pub stopping: bool,
+}
+
+pub enum TokenOperation {
+  Create(NewToken),
+  List,
+  Revoke(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct RunEntry {
+  pub entry: EntryIdentifier,
+  pub args: Option<Vec<String>>,
+  pub env: Option<HashMap<String, String>>,
+}
+
+/// What became of a run asked for through runEntry.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+#[serde(tag = "outcome")]
+pub enum RunOutcome {
+  Started,
+  /// A limit was reached, the run starts once the limits allow it.
+  Queued {
+    reason: String,
+  },
+  /// The entry was running and set to stop in that case.
+  Stopped,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub enum Logger {
   File(String),
+  #[default]
   Default,
   Off,
 }
 
-impl Default for Logger {
-  fn default() -> Self {
-    Self::Default
-  }
-}
-
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub enum Timer {
   Repeat(Duration),
   Once(DateTime),
@@ -147,42 +640,160 @@ pub enum Timer {
   Never,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct UnixUser {
   pub uid: u32,
   pub gid: u32,
   pub username: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct WindowsUser {
   pub username: String,
   pub group_windows: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub enum SystemUser {
   Unix(UnixUser),
   Windows(WindowsUser),
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub struct Execute {
+  /// Values may use the variables set before, see `utils::expand_vars`.
   pub env: Option<HashMap<String, String>>,
+  /// Which variables of the daemon's environment the run inherits.
+  #[serde(default)]
+  pub inherit_env: InheritEnv,
+  /// Dotenv files loaded in order between the inherited variables and `env`,
+  /// read again for every run.
+  #[serde(default)]
+  pub env_files: Vec<String>,
   pub working_dir: Option<String>,
+  /// The interpreter of a script, `/bin/sh` if empty.
+  #[serde(default)]
+  #[schema(value_type = String)]
   pub executable: PathBuf,
   pub user: Option<SystemUser>,
   pub args: Option<Vec<String>>,
+  /// Written to the run's standard input, which is then closed.
+  #[serde(default)]
+  pub stdin: Option<String>,
+  #[serde(default)]
+  pub limits: Option<Box<Limits>>,
+  #[serde(default)]
+  pub sandbox: Option<Box<Sandbox>>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+/// Hardening of a run, like the options of the same name of systemd services.
+/// Namespaces and capabilities need the daemon to run as root.
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+#[serde(default)]
+pub struct Sandbox {
+  /// Empty `/tmp` and `/var/tmp` only the run sees.
+  pub private_tmp: bool,
+  /// Paths the run can read but not write, `private_tmp` hides those in `/tmp`.
+  pub read_only_paths: Vec<String>,
+  /// Keeps setuid binaries and file capabilities from granting privileges.
+  pub no_new_privileges: bool,
+  /// Capabilities the run keeps, like `CAP_NET_BIND_SERVICE`, all others are
+  /// dropped. `None` keeps them all.
+  pub capabilities: Option<Vec<String>>,
+  /// A network namespace with only a loopback interface, which is down.
+  pub private_network: bool,
+  /// Implies `no_new_privileges`.
+  pub seccomp: Option<Seccomp>,
+}
+
+/// System calls denied to a run, they fail with EPERM.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub enum Seccomp {
+  /// Those changing the system as a whole: mounts, modules, reboot, swap,
+  /// clock, kexec, tracing other processes and the like.
+  System,
+  /// By name, denying `execve` keeps the job from starting at all.
+  Deny(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+pub enum InheritEnv {
+  #[default]
+  All,
+  /// Starts from an empty environment.
+  Clean,
+  /// Only the variables named.
+  Only(Vec<String>),
+}
+
+/// Resource limits of a run. The rlimits apply to each of its processes, the
+/// cgroup ones to the whole run, which doesn't start without a cgroup to
+/// enforce them in, see `Config::cgroup`.
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+#[serde(default)]
+pub struct Limits {
+  /// Seconds of CPU time, the process gets SIGXCPU then SIGKILL a second later.
+  pub cpu_time: Option<u64>,
+  /// Bytes of virtual memory.
+  pub address_space: Option<u64>,
+  pub open_files: Option<u64>,
+  /// Processes of the run's user, counted across the whole system.
+  pub processes: Option<u64>,
+  /// Bytes of memory of the whole run, `memory.max`.
+  pub memory: Option<u64>,
+  /// CPUs the whole run may use, e.g. 0.5, `cpu.max`.
+  pub cpus: Option<f64>,
+  /// Processes in the whole run, `pids.max`.
+  pub pids: Option<u64>,
+  /// From -20 to 19, below 0 needs privileges.
+  pub nice: Option<i32>,
+  pub ionice: Option<IoNice>,
+}
+
+/// I/O scheduling class, with the priority from 0 (highest) to 7.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub enum IoNice {
+  Realtime(u8),
+  BestEffort(u8),
+  Idle,
+}
+
+/// A script run by the interpreter in `exec.executable`.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct Script {
+  pub source: String,
+  #[serde(default)]
+  pub input: ScriptInput,
+  #[serde(flatten)]
+  pub exec: Execute,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+pub enum ScriptInput {
+  /// A file in the output directory passed to the interpreter before
+  /// `exec.args`, which `Sandbox::private_tmp` hides if that is in `/tmp`.
+  #[default]
+  File,
+  /// The interpreter reads the script from its standard input.
+  Stdin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub enum Action {
   Exec(Execute),
+  Script(Script),
   #[default]
   None,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+pub enum OverflowPolicy {
+  #[default]
+  Queue,
+  Drop,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub enum DoIfRunning {
   #[default]
   StartNew,
@@ -191,7 +802,7 @@ pub enum DoIfRunning {
   Continue,
 }
 
-#[derive(Serialize, Deserialize, Default, Clone)]
+#[derive(Serialize, Deserialize, Default, Clone, ToSchema)]
 pub struct Entry {
   pub id: u32,
   pub name: String,
@@ -201,6 +812,25 @@ pub struct Entry {
   pub status: Status,
   pub do_if_running: DoIfRunning,
   pub enabled: bool,
+  /// Upper bound in seconds of a random delay added to every scheduled run.
+  #[serde(default)]
+  pub jitter: u32,
+  /// Upper bound in seconds of a fixed delay derived from the hostname.
+  #[serde(default)]
+  pub splay: u32,
+  /// Paused entries resume on their own once this time is up.
+  #[serde(default)]
+  pub paused_until: Option<DateTime>,
+  #[serde(default)]
+  pub max_instances: Option<u32>,
+  #[serde(default)]
+  pub overflow_policy: OverflowPolicy,
+  /// Named locks that must be free before a run of this entry starts.
+  #[serde(default)]
+  pub locks: Vec<String>,
+  /// Free-form labels, tokens can be limited to the entries having one.
+  #[serde(default)]
+  pub tags: Vec<String>,
 }
 
 pub enum OperationType {
@@ -208,40 +838,60 @@ pub enum OperationType {
   Delete,
   Start,
   Pause,
+  Resume,
+  Skip,
+  Watch,
+  Logs,
+  Token,
   StartDaemon,
   StopDaemon,
+  Status,
+  SystemdUnit,
   List,
   Detail,
   Help,
   Version,
 }
 
+#[allow(dead_code)]
 pub enum Operation {
-  Add(Entry),
+  Add(Box<Entry>),
   Delete(EntryIdentifier),
-  Start(EntryIdentifier),
-  Pause(EntryIdentifier),
-  StartDaemon(),
+  Start(RunEntry),
+  Pause(PauseEntry),
+  Resume(EntryIdentifier),
+  Skip(EntryIdentifier),
+  Watch(Option<String>),
+  Logs(LogsEntry),
+  Token(TokenOperation),
+  StartDaemon(DaemonOptions),
   StopDaemon(),
+  Status(),
+  SystemdUnit(UnitKind),
   List(),
   Detail(EntryIdentifier),
   Help(Option<OperationType>),
   Version,
 }
 
+#[allow(dead_code)]
 pub trait CommandHelp {
   fn cmd_help() -> String;
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub enum Trigger {
   Timer(Timer),
   #[default]
   None,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
 pub struct TriggerState {
   pub exec_time: Option<DateTime>,
   pub exec_times: u32,
+  #[serde(default)]
+  pub delay: u32,
+  #[serde(default)]
+  pub effective_exec_time: Option<DateTime>,
 }
@@ -1,13 +1,37 @@
 use crate::types::*;
-use log::info;
+use actix_web::{http::header, HttpRequest};
+use log::warn;
 #[cfg(target_family = "unix")]
-use nix::{sys::signal::kill, unistd::Pid};
+use nix::{
+  libc,
+  sys::{
+    signal::kill,
+    wait::{waitpid, WaitPidFlag, WaitStatus},
+  },
+  unistd::{geteuid, Pid},
+};
 use rand::Rng;
+use reqwest::blocking::ClientBuilder;
+use reqwest::{Certificate, Identity};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::{
+  collections::HashMap,
+  error::Error,
+  fs, io,
+  net::SocketAddr,
+  os::unix::net::UnixStream,
+  path::{Path, PathBuf},
   str::FromStr,
-  sync::{RwLockReadGuard, RwLockWriteGuard},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+  },
 };
+use subtle::ConstantTimeEq;
+use sysinfo::SystemExt;
 
 pub fn generate_token() -> String {
   let mut rng = rand::thread_rng();
@@ -33,23 +57,144 @@ pub fn check_if_help_in_args(args: &[String]) -> bool {
   false
 }
 
-pub fn check_token(data: &ReqData, rtodo: &Rtodo) -> bool {
-  #[cfg(debug_assertions)]
-  info!(
-    "Info: token in request: {}, token in rtodo: {}",
-    data
-      .get("token")
-      .unwrap_or(&serde_json::Value::Null)
-      .as_str()
-      .unwrap_or(""),
-    rtodo.get_token()
-  );
-  data
-    .get("token")
-    .unwrap_or(&serde_json::Value::Null)
-    .as_str()
-    .unwrap_or("")
-    == rtodo.get_token()
+/// Hex SHA-256 of `salt` followed by `token`.
+pub fn hash_token(salt: &str, token: &str) -> String {
+  Sha256::new()
+    .chain_update(salt)
+    .chain_update(token)
+    .finalize()
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect()
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much
+/// of a guessed token was right.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+  a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+pub fn request_token(data: &ReqData) -> &str {
+  &data.token
+}
+
+/// Checks the token of a legacy request body.
+pub fn check_token(data: &ReqData, rtodo: &Rtodo, scope: TokenScope) -> Result<Grant, String> {
+  rtodo.authorize(request_token(data), scope)
+}
+
+/// Builds the CLI's client for the daemon and the base URL of its API. The
+/// Unix socket is preferred when it accepts connections, the URL then only
+/// serves as the Host header.
+pub fn daemon_client(config: &Config) -> Result<(ClientBuilder, String), Box<dyn Error>> {
+  let builder = reqwest::blocking::Client::builder();
+  if let Some(path) = &config.socket_path {
+    if UnixStream::connect(path).is_ok() {
+      return Ok((
+        builder.unix_socket(path.clone()),
+        format!("http://{}/api", config.address),
+      ));
+    }
+  }
+  let tls = match &config.tls {
+    Some(tls) => tls,
+    None => return Ok((builder, format!("http://{}/api", config.address))),
+  };
+  let mut builder = builder.use_rustls_tls();
+  if let Some(ca) = &tls.ca {
+    for cert in Certificate::from_pem_bundle(&fs::read(ca)?)? {
+      builder = builder.add_root_certificate(cert);
+    }
+  }
+  if let (Some(cert), Some(key)) = (&tls.client_cert, &tls.client_key) {
+    let mut pem = fs::read(cert)?;
+    pem.extend(fs::read(key)?);
+    builder = builder.identity(Identity::from_pem(&pem)?);
+  }
+  let host = match &tls.server_name {
+    Some(name) => {
+      let addr: SocketAddr = config.address.parse()?;
+      builder = builder.resolve(name, addr);
+      format!("{}:{}", name, addr.port())
+    }
+    None => config.address.clone(),
+  };
+  Ok((builder, format!("https://{}/api", host)))
+}
+
+/// The bearer token of a request. Browsers can't set headers on an
+/// `EventSource` or a WebSocket, so the event streams pass the `token` query
+/// parameter as `query_token`, used only without the header.
+pub fn api_token(req: &HttpRequest, query_token: Option<&str>) -> Option<String> {
+  req
+    .headers()
+    .get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    .or(query_token)
+    .map(String::from)
+}
+
+pub fn check_request_token(
+  token: Option<&str>,
+  rtodo: &Rtodo,
+  scope: TokenScope,
+) -> Result<Grant, ApiError> {
+  let grant = request_grant(token, rtodo)?;
+  grant.require(scope).map_err(ApiError::forbidden)?;
+  Ok(grant)
+}
+
+/// Only checks that the token is valid, routes that change something check
+/// the scope as part of the operation so that denials get audited.
+pub fn request_grant(token: Option<&str>, rtodo: &Rtodo) -> Result<Grant, ApiError> {
+  token
+    .and_then(|token| rtodo.authenticate(token))
+    .ok_or_else(ApiError::unauthorized)
+}
+
+/// Parses a single `bytes=` range into a half-open interval within `size`,
+/// `None` for anything else since such headers are to be ignored.
+pub fn parse_byte_range(value: &str, size: u64) -> Option<(u64, u64)> {
+  let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+  match (start.trim(), end.trim()) {
+    ("", suffix) => Some((size.saturating_sub(suffix.parse().ok()?), size)),
+    (start, "") => Some((start.parse().ok()?, size)),
+    (start, end) => {
+      let (start, end) = (start.parse().ok()?, end.parse::<u64>().ok()?);
+      if end < start {
+        return None;
+      }
+      Some((start, size.min(end + 1)))
+    }
+  }
+}
+
+/// Applies a JSON merge patch (RFC 7396) to `target`.
+pub fn json_merge(target: &mut serde_json::Value, patch: &serde_json::Value) {
+  let patch = match patch.as_object() {
+    Some(patch) => patch,
+    None => {
+      *target = patch.clone();
+      return;
+    }
+  };
+  // An object patch applies to `{}` if the target is no object, so its nulls
+  // still delete rather than end up stored.
+  if !target.is_object() {
+    *target = serde_json::Value::Object(serde_json::Map::new());
+  }
+  let target = target.as_object_mut().unwrap();
+  for (key, value) in patch {
+    if value.is_null() {
+      target.remove(key);
+    } else {
+      json_merge(
+        target.entry(key.clone()).or_insert(serde_json::Value::Null),
+        value,
+      );
+    }
+  }
 }
 
 pub fn nerr(code: i32, msg: &str) -> String {
@@ -64,7 +209,7 @@ pub fn nsucc<T: Serialize>(code: i32, data: T) -> String {
 
 pub fn garg<T: FromStr>(args: &[String], index: usize) -> Option<T> {
   let err = "Invalid argument";
-  match (match args.get(index).ok_or(err) {
+  (match args.get(index).ok_or(err) {
     Ok(data) => data,
     Err(_) => {
       return None;
@@ -72,46 +217,451 @@ pub fn garg<T: FromStr>(args: &[String], index: usize) -> Option<T> {
   })
   .clone()
   .parse::<T>()
-  {
-    Ok(data) => Some(data),
-    Err(_) => None,
+  .ok()
+}
+
+/// Splits `line` into words like a POSIX shell, without expansions: quotes
+/// group words, backslashes escape outside single quotes.
+pub fn split_args(line: &str) -> Result<Vec<String>, String> {
+  let mut words = Vec::new();
+  let mut word: Option<String> = None;
+  let mut chars = line.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      c if c.is_whitespace() => words.extend(word.take()),
+      '\'' => {
+        let word = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => word.push(c),
+            None => return Err("Unterminated single quote".to_string()),
+          }
+        }
+      }
+      '"' => {
+        let word = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+              Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+              Some('\n') => (),
+              Some(c) => word.extend(['\\', c]),
+              None => return Err("Unterminated double quote".to_string()),
+            },
+            Some(c) => word.push(c),
+            None => return Err("Unterminated double quote".to_string()),
+          }
+        }
+      }
+      '\\' => match chars.next() {
+        Some('\n') => (),
+        Some(c) => word.get_or_insert_with(String::new).push(c),
+        None => return Err("Trailing backslash".to_string()),
+      },
+      c => word.get_or_insert_with(String::new).push(c),
+    }
+  }
+  words.extend(word);
+  Ok(words)
+}
+
+/// Reads `KEY=value` pairs split like `split_args`, so values may be quoted.
+pub fn env_pairs(line: &str) -> Result<HashMap<String, String>, String> {
+  split_args(line)?
+    .into_iter()
+    .map(|pair| match pair.split_once('=') {
+      Some((key, value)) => Ok((key.to_string(), value.to_string())),
+      None => Err(format!("Expected KEY=value, got {}", pair)),
+    })
+    .collect()
+}
+
+/// Reads a byte count, optionally with a `K`, `M`, `G` or `T` suffix in
+/// powers of 1024, like `512M`.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+  let (digits, shift) = match value.char_indices().last() {
+    Some((index, 'K' | 'k')) => (&value[..index], 10),
+    Some((index, 'M' | 'm')) => (&value[..index], 20),
+    Some((index, 'G' | 'g')) => (&value[..index], 30),
+    Some((index, 'T' | 't')) => (&value[..index], 40),
+    _ => (value, 0),
+  };
+  digits
+    .parse::<u64>()
+    .ok()
+    .and_then(|count| count.checked_mul(1 << shift))
+    .ok_or_else(|| format!("Invalid size {}", value))
+}
+
+/// Replaces `$NAME` and `${NAME}` in `value` with `lookup(NAME)`, or nothing
+/// for unset variables, and `$$` with `$`.
+pub fn expand_vars(value: &str, lookup: &mut dyn FnMut(&str) -> Option<String>) -> String {
+  let mut expanded = String::new();
+  let mut chars = value.chars().peekable();
+  while let Some(c) = chars.next() {
+    if c != '$' {
+      expanded.push(c);
+      continue;
+    }
+    match chars.peek() {
+      Some('$') => {
+        chars.next();
+        expanded.push('$');
+      }
+      Some('{') => {
+        chars.next();
+        let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+        expanded.push_str(&lookup(&name).unwrap_or_default());
+      }
+      Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+          if !c.is_ascii_alphanumeric() && c != '_' {
+            break;
+          }
+          name.push(c);
+          chars.next();
+        }
+        expanded.push_str(&lookup(&name).unwrap_or_default());
+      }
+      _ => expanded.push('$'),
+    }
   }
+  expanded
+}
+
+/// Reads the `KEY=value` lines of a dotenv file into `vars`. Values may be
+/// quoted, single quotes keep them as they are, otherwise they may use the
+/// variables set before them.
+pub fn parse_dotenv(content: &str, vars: &mut HashMap<String, String>) -> Result<(), String> {
+  for (index, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let err = |reason: &str| format!("{} on line {}", reason, index + 1);
+    let (key, value) = line
+      .split_once('=')
+      .ok_or_else(|| err("Expected KEY=value"))?;
+    let key = key.trim();
+    if key.is_empty()
+      || key.starts_with(|c: char| c.is_ascii_digit())
+      || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+      return Err(err("Invalid variable name"));
+    }
+    let value = value.trim_start();
+    let (value, rest) = if let Some(quoted) = value.strip_prefix('\'') {
+      let end = quoted
+        .find('\'')
+        .ok_or_else(|| err("Unterminated single quote"))?;
+      (quoted[..end].to_string(), &quoted[end + 1..])
+    } else if let Some(quoted) = value.strip_prefix('"') {
+      let mut unescaped = String::new();
+      let mut chars = quoted.char_indices();
+      let end = loop {
+        match chars.next() {
+          Some((end, '"')) => break end,
+          Some((_, '\\')) => match chars.next() {
+            Some((_, 'n')) => unescaped.push('\n'),
+            // Kept for `expand_vars`.
+            Some((_, '$')) => unescaped.push_str("$$"),
+            Some((_, c)) => unescaped.push(c),
+            None => return Err(err("Unterminated double quote")),
+          },
+          Some((_, c)) => unescaped.push(c),
+          None => return Err(err("Unterminated double quote")),
+        }
+      };
+      (
+        expand_vars(&unescaped, &mut |name| vars.get(name).cloned()),
+        &quoted[end + 1..],
+      )
+    } else {
+      let value = match value.find(" #") {
+        Some(comment) => &value[..comment],
+        None => value,
+      };
+      (
+        expand_vars(value.trim_end(), &mut |name| vars.get(name).cloned()),
+        "",
+      )
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+      return Err(err("Unexpected text after the closing quote"));
+    }
+    vars.insert(key.to_string(), value);
+  }
+  Ok(())
+}
+
+pub fn host_name() -> &'static str {
+  static HOST_NAME: OnceLock<String> = OnceLock::new();
+  HOST_NAME.get_or_init(|| sysinfo::System::new().host_name().unwrap_or_default())
+}
+
+// FNV-1a, unlike `DefaultHasher` it gives the same result on every build.
+pub fn stable_hash(data: &str) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for byte in data.bytes() {
+    hash ^= byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
+}
+
+static OUTPUT_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Directory of the captured output of runs whose entry uses the default
+/// logger, and of inline scripts. Set up by `init_output_dir`.
+pub fn output_dir() -> PathBuf {
+  OUTPUT_DIR
+    .get()
+    .cloned()
+    .expect("The output directory is set up when the daemon starts")
+}
+
+/// Creates the output directory, or checks that the daemon's user owns it,
+/// so that no one else can plant files or links where output gets written.
+#[cfg(target_family = "unix")]
+pub fn init_output_dir(path: &Path) -> Result<(), Box<dyn Error>> {
+  fs::DirBuilder::new()
+    .recursive(true)
+    .mode(0o700)
+    .create(path)?;
+  let meta = fs::symlink_metadata(path)?;
+  if !meta.is_dir() {
+    return Err(format!("Output directory {} is not a directory", path.display()).into());
+  }
+  if meta.uid() != geteuid().as_raw() {
+    return Err(
+      format!(
+        "Output directory {} is owned by uid {}, not the daemon's",
+        path.display(),
+        meta.uid()
+      )
+      .into(),
+    );
+  }
+  if meta.mode() & 0o077 != 0 {
+    warn!(
+      "Warn: Output directory {} was accessible to others, restricting it to 0700",
+      path.display()
+    );
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+  }
+  // Jobs get paths in it, from a working directory of their own.
+  OUTPUT_DIR
+    .set(fs::canonicalize(path)?)
+    .map_err(|_| "The output directory is already set up".into())
+}
+
+/// Opens a new file in the output directory, failing if something is there.
+#[cfg(target_family = "unix")]
+pub fn create_output_file(path: &Path) -> io::Result<fs::File> {
+  fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(0o600)
+    .custom_flags(libc::O_NOFOLLOW)
+    .open(path)
+}
+
+/// Run ids continue after the highest one left in the output directory, so
+/// they stay unique across daemon restarts.
+pub fn next_run_id() -> u64 {
+  static RUN_SEQ: OnceLock<AtomicU64> = OnceLock::new();
+  RUN_SEQ
+    .get_or_init(|| {
+      let max = fs::read_dir(output_dir())
+        .into_iter()
+        .flatten()
+        .filter_map(|file| file.ok()?.path().file_stem()?.to_str()?.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0);
+      AtomicU64::new(max + 1)
+    })
+    .fetch_add(1, Ordering::Relaxed)
 }
 
 pub fn random_name() -> String {
   "Not impled".to_string()
 }
 
+/// Returns `None` while the process is alive, otherwise whether it succeeded.
 #[cfg(target_family = "unix")]
-pub fn check_if_process_by_pid_alive(pid: i32) -> bool {
-  match kill(Pid::from_raw(pid), None) {
-    Ok(_) => true,
-    Err(_) => false,
+pub fn reap_process(pid: i32) -> Option<ExitResult> {
+  // Reap our own exited children first, otherwise they linger as zombies
+  // and still answer to kill(pid, 0).
+  match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+    Ok(WaitStatus::Exited(_, 0)) => Some(Ok(())),
+    Ok(WaitStatus::Exited(_, code)) => Some(Err(format!("exited with code {}", code))),
+    Ok(WaitStatus::Signaled(_, signal, _)) => Some(Err(format!("killed by {}", signal))),
+    Ok(_) => None,
+    // Not our child, its exit status is unknown.
+    Err(_) => match kill(Pid::from_raw(pid), None) {
+      Ok(_) => None,
+      Err(_) => Some(Ok(())),
+    },
   }
 }
 
-pub async fn get_rtodo_read_gurad(state: &RS) -> RwLockReadGuard<Rtodo> {
-  loop {
-    match state.rtodo.try_read() {
-      Ok(data) => break data,
-      Err(_) => tokio::time::sleep(tokio::time::Duration::from_millis(100)).await,
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn merged(mut target: serde_json::Value, patch: serde_json::Value) -> serde_json::Value {
+    json_merge(&mut target, &patch);
+    target
+  }
+
+  #[test]
+  fn json_merge_deletes_nulls() {
+    assert_eq!(
+      merged(json!({"a": 1, "b": 2}), json!({"a": null, "c": 3})),
+      json!({"b": 2, "c": 3})
+    );
+    assert_eq!(merged(json!({"a": 1}), json!({"b": null})), json!({"a": 1}));
+  }
+
+  #[test]
+  fn json_merge_recurses_into_objects() {
+    assert_eq!(
+      merged(
+        json!({"a": {"b": 1, "c": 2}, "d": [1, 2]}),
+        json!({"a": {"b": null, "e": {"f": null, "g": 3}}, "d": [3]})
+      ),
+      json!({"a": {"c": 2, "e": {"g": 3}}, "d": [3]})
+    );
+  }
+
+  #[test]
+  fn json_merge_replaces_non_objects() {
+    assert_eq!(
+      merged(json!([1, 2]), json!({"a": {"b": null}, "c": null})),
+      json!({"a": {}})
+    );
+    assert_eq!(merged(json!({"a": 1}), json!("x")), json!("x"));
+    assert_eq!(
+      merged(json!({"a": "x"}), json!({"a": {"b": null}})),
+      json!({"a": {}})
+    );
+  }
+
+  #[test]
+  fn parse_byte_range_open_ranges() {
+    assert_eq!(parse_byte_range("bytes=10-19", 100), Some((10, 20)));
+    assert_eq!(parse_byte_range("bytes=90-", 100), Some((90, 100)));
+    assert_eq!(parse_byte_range("bytes=-10", 100), Some((90, 100)));
+    assert_eq!(parse_byte_range("bytes=-200", 100), Some((0, 100)));
+    assert_eq!(parse_byte_range("bytes=50-500", 100), Some((50, 100)));
+  }
+
+  #[test]
+  fn parse_byte_range_ignores_invalid_ranges() {
+    for value in [
+      "bytes=20-10",
+      "items=0-1",
+      "bytes=a-b",
+      "bytes=1",
+      "bytes=-",
+      "bytes=0-1,5-6",
+    ] {
+      assert_eq!(parse_byte_range(value, 100), None, "{}", value);
     }
   }
-}
 
-pub async fn get_rtodo_write_gurad(state: &RS) -> RwLockWriteGuard<Rtodo> {
-  loop {
-    match state.rtodo.try_write() {
-      Ok(data) => {
-        #[cfg(debug_assertions)]
-        info!(
-          "Info: got write lock of works at line:{}, file: {}",
-          line!(),
-          file!()
-        );
-        break data;
-      }
-      Err(_) => tokio::time::sleep(tokio::time::Duration::from_millis(100)).await,
+  #[test]
+  fn hash_token_salts_the_token() {
+    // SHA-256 of "abc".
+    let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    assert_eq!(hash_token("", "abc"), abc);
+    assert_eq!(hash_token("a", "bc"), abc);
+    assert_ne!(hash_token("salt", "abc"), hash_token("other", "abc"));
+  }
+
+  #[test]
+  fn split_args_quotes_and_escapes() {
+    assert_eq!(
+      split_args(r#" a  'b c' "d \"e\" \$f \g" h\ i '\n' "" "#),
+      Ok(vec![
+        "a".to_string(),
+        "b c".to_string(),
+        r#"d "e" $f \g"#.to_string(),
+        "h i".to_string(),
+        r"\n".to_string(),
+        String::new(),
+      ])
+    );
+    assert_eq!(split_args("a'b'\"c\"d"), Ok(vec!["abcd".to_string()]));
+    assert_eq!(
+      split_args("a \\\nb"),
+      Ok(vec!["a".to_string(), "b".to_string()])
+    );
+  }
+
+  #[test]
+  fn split_args_rejects_unterminated_quotes() {
+    assert!(split_args("a 'b").is_err());
+    assert!(split_args("a \"b").is_err());
+    assert!(split_args("a \"b\\").is_err());
+    assert!(split_args("a\\").is_err());
+  }
+
+  fn expanded(value: &str) -> String {
+    expand_vars(value, &mut |name| match name {
+      "HOME" => Some("/root".to_string()),
+      "A_1" => Some("x".to_string()),
+      _ => None,
+    })
+  }
+
+  #[test]
+  fn expand_vars_forms() {
+    assert_eq!(expanded("$HOME/bin"), "/root/bin");
+    assert_eq!(expanded("${HOME}bin"), "/rootbin");
+    assert_eq!(expanded("$A_1-$UNSET-${UNSET}."), "x--.");
+    assert_eq!(expanded("$$HOME $$$A_1"), "$HOME $x");
+    assert_eq!(expanded("$ $1 cost$"), "$ $1 cost$");
+  }
+
+  #[test]
+  fn parse_dotenv_lines() {
+    let mut vars = HashMap::from([("BASE".to_string(), "/srv".to_string())]);
+    parse_dotenv(
+      r#"
+# a comment
+export PLAIN=value # trailing comment
+DIR=$BASE/app
+SINGLE='$BASE # kept'
+DOUBLE="${DIR} \"quoted\"\n\$BASE" # comment
+EMPTY=
+"#,
+      &mut vars,
+    )
+    .unwrap();
+    assert_eq!(vars["PLAIN"], "value");
+    assert_eq!(vars["DIR"], "/srv/app");
+    assert_eq!(vars["SINGLE"], "$BASE # kept");
+    assert_eq!(vars["DOUBLE"], "/srv/app \"quoted\"\n$BASE");
+    assert_eq!(vars["EMPTY"], "");
+  }
+
+  #[test]
+  fn parse_dotenv_rejects_invalid_lines() {
+    for content in [
+      "NO_VALUE", "1ST=a", "A-B=a", "A='open", "A=\"open", "A='a' b",
+    ] {
+      assert!(
+        parse_dotenv(content, &mut HashMap::new()).is_err(),
+        "{}",
+        content
+      );
     }
   }
 }